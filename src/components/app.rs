@@ -4,14 +4,14 @@ use leptos::*;
 use leptos_meta::{provide_meta_context, Title};
 use leptos_router::*;
 use log::info;
-use web_sys::File;
 
 use crate::{
     components::{
-        footer::Footer, header::Header, menu::Menu, receive::ReceiveFile, send::SendFile,
-        settings::Settings,
+        footer::Footer, header::Header, history::TransferHistory, menu::Menu, receive::ReceiveFile,
+        send::SendFile, settings::Settings, swarm::remove_stale_seeds,
     },
-    peerjs::peerid::PeerID,
+    crypto::parse_share_code,
+    files::SelectedFile,
 };
 
 pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
@@ -22,18 +22,24 @@ pub(crate) fn App() -> impl IntoView {
 
     provide_meta_context();
 
+    spawn_local(remove_stale_seeds());
+
     let settings = Settings::load_or_default();
     let (settings, set_settings) = create_signal(Rc::new(settings));
     provide_context(settings);
     provide_context(set_settings);
 
-    let (file_to_send, set_file_to_send) = create_signal::<FileToSend>(FileToSend(None));
+    let (file_to_send, set_file_to_send) = create_signal::<FileToSend>(FileToSend(Vec::new()));
     provide_context(file_to_send);
     provide_context(set_file_to_send);
 
+    let (text_to_send, set_text_to_send) = create_signal::<TextToSend>(TextToSend(None));
+    provide_context(text_to_send);
+    provide_context(set_text_to_send);
+
     let location = web_sys::window().unwrap().location();
     let location_hash = location.hash().unwrap();
-    if hash_is_peer_id(&location_hash) {
+    if hash_is_share_code(&location_hash) {
         let receive_endpoint = format!("#/receive/{}", location_hash.trim_start_matches('#'));
         location.set_hash(&receive_endpoint).unwrap();
     }
@@ -49,7 +55,8 @@ pub(crate) fn App() -> impl IntoView {
                     <Routes>
                         <Route path="/" view=Menu/>
                         <Route path="/send" view=SendFile/>
-                        <Route path="/receive/:peer_id" view=ReceiveFile/>
+                        <Route path="/receive/:code" view=ReceiveFile/>
+                        <Route path="/history" view=TransferHistory/>
                     </Routes>
                 </div>
                 <Footer/>
@@ -59,9 +66,14 @@ pub(crate) fn App() -> impl IntoView {
 }
 
 #[derive(Clone)]
-pub struct FileToSend(pub Option<File>);
+pub struct FileToSend(pub Vec<SelectedFile>);
+
+/// Text to share instead of files
+#[derive(Clone)]
+pub struct TextToSend(pub Option<String>);
 
-fn hash_is_peer_id(hash: &str) -> bool {
+/// Whether the hash is a sharing link's `ABCD.<key>` or `ABCD` code rather than a route
+fn hash_is_share_code(hash: &str) -> bool {
     let trimmed = hash.strip_prefix('#').unwrap_or(hash);
-    trimmed.len() == 4 && PeerID::valid_base(trimmed)
+    parse_share_code(trimmed).is_some()
 }
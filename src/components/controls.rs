@@ -0,0 +1,93 @@
+use leptos::*;
+use tokio::sync::mpsc;
+
+use crate::{
+    crypto::Cipher,
+    protocol::{send_message, Link, Message},
+};
+
+/// What the user can do to the transfers on a connection
+#[derive(Clone, Copy)]
+pub(crate) enum Control {
+    Pause,
+    Resume,
+    Cancel,
+}
+
+/// Which peer paused the transfers
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Paused {
+    Here,
+    ByPeer,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Cancelled")]
+pub(crate) struct Cancelled;
+
+/// The user's controls waiting to be acted on, and whether transfers are paused. Either peer can
+/// pause or resume, and the other is told about every change
+pub(crate) struct Controls {
+    control_rx: mpsc::UnboundedReceiver<Control>,
+    paused: RwSignal<Option<Paused>>,
+    cancelled: bool,
+}
+
+impl Controls {
+    pub fn new(
+        control_rx: mpsc::UnboundedReceiver<Control>,
+        paused: RwSignal<Option<Paused>>,
+    ) -> Controls {
+        Controls {
+            control_rx,
+            paused,
+            cancelled: false,
+        }
+    }
+
+    pub async fn recv(&mut self) -> Option<Control> {
+        self.control_rx.recv().await
+    }
+
+    /// Whether chunks should be held back
+    pub fn is_paused(&self) -> bool {
+        self.paused.get_untracked().is_some()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Acts on the user's control and tells the peer. Cancelling ends the transfers, so it's
+    /// returned as an error
+    pub fn apply(
+        &mut self,
+        link: &Link,
+        cipher: &Cipher,
+        control: Control,
+    ) -> Result<(), Cancelled> {
+        match control {
+            Control::Pause if !self.is_paused() => {
+                send_message(link, cipher, &Message::Pause);
+                self.paused.set(Some(Paused::Here));
+            }
+            Control::Resume if self.is_paused() => {
+                send_message(link, cipher, &Message::Resume);
+                self.paused.set(None);
+            }
+            Control::Cancel => {
+                send_message(link, cipher, &Message::Cancel);
+                self.cancelled = true;
+                return Err(Cancelled);
+            }
+            Control::Pause | Control::Resume => {}
+        }
+
+        Ok(())
+    }
+
+    /// Acts on a pause or resume from the peer
+    pub fn peer_paused(&self, paused: bool) {
+        self.paused.set(paused.then_some(Paused::ByPeer));
+    }
+}
@@ -0,0 +1,136 @@
+use gloo_utils::format::JsValueSerdeExt;
+use js_sys::{Array, Date, JSON};
+use leptos::*;
+use leptos_meta::Title;
+use log::error;
+use wasm_bindgen::JsValue;
+use web_sys::{Blob, BlobPropertyBag};
+
+use crate::{
+    history::{HistoryDirection, HistoryEntry, HistoryStore, Outcome},
+    sink::download_blob,
+    utils::{format_bytes, format_duration, jserror},
+};
+
+const EXPORT_FILENAME: &str = "transfer-history.json";
+
+#[component]
+pub(crate) fn TransferHistory() -> impl IntoView {
+    let entries = create_rw_signal(Vec::<HistoryEntry>::new());
+    let status = create_rw_signal(Some("Loading".to_string()));
+    let search = create_rw_signal(String::new());
+
+    spawn_local(async move {
+        let loaded = match HistoryStore::open().await {
+            Ok(store) => store.all().await,
+            Err(error) => Err(error),
+        };
+        match loaded {
+            Ok(loaded) => {
+                status.set(
+                    loaded
+                        .is_empty()
+                        .then(|| "Nothing sent or received yet".to_string()),
+                );
+                entries.set(loaded);
+            }
+            Err(error) => status.set(Some(format!("Error loading history: {error}"))),
+        }
+    });
+
+    // Newest first
+    let shown = create_memo(move |_| {
+        let search = search.get();
+        entries.with(|entries| {
+            entries
+                .iter()
+                .rev()
+                .filter(|entry| entry.matches(&search))
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+    });
+
+    let on_export_click = move |_| shown.with_untracked(|shown| export(shown));
+
+    view! {
+        <div>
+            <Title text="History"/>
+            <div>"History"</div>
+            <input
+                type="search"
+                placeholder="Search by name, peer ID or hash"
+                prop:value=move || search.get()
+                on:input=move |event| search.set(event_target_value(&event))
+            />
+            <div on:click=on_export_click>"Export JSON"</div>
+            <div>{move || status.get()}</div>
+            <For
+                each=move || shown.get()
+                key=|entry| entry.id
+                children=move |entry| entry_view(entry, entries)
+            />
+        </div>
+    }
+}
+
+fn entry_view(entry: HistoryEntry, entries: RwSignal<Vec<HistoryEntry>>) -> impl IntoView {
+    let id = entry.id;
+    let on_delete_click = move |_| {
+        spawn_local(async move {
+            let removed = match HistoryStore::open().await {
+                Ok(store) => store.remove(id).await,
+                Err(error) => Err(error),
+            };
+            match removed {
+                Ok(()) => entries.update(|entries| entries.retain(|entry| entry.id != id)),
+                Err(error) => error!("Error deleting history entry: {error}"),
+            }
+        });
+    };
+
+    let direction = match entry.direction {
+        HistoryDirection::Sent => format!("Sent to {}", entry.peer_id),
+        HistoryDirection::Received => format!("Received from {}", entry.peer_id),
+    };
+    let outcome = match (entry.outcome, &entry.error) {
+        (Outcome::Completed, _) => "Completed".to_string(),
+        (Outcome::Declined, _) => "Declined".to_string(),
+        (Outcome::Failed, Some(error)) => format!("Failed: {error}"),
+        (Outcome::Failed, None) => "Failed".to_string(),
+    };
+    let started = String::from(
+        Date::new(&entry.started.into()).to_locale_string("default", &JsValue::UNDEFINED),
+    );
+    let duration = format_duration((entry.ended - entry.started) / 1000.0);
+
+    view! {
+        <div>
+            <div>{entry.path}</div>
+            <div>{format_bytes(entry.size)}</div>
+            <div>{direction}</div>
+            <div>{format!("{started}, took {duration}")}</div>
+            <div>{outcome}</div>
+            <div>{format!("SHA-256: {}", entry.hash)}</div>
+            <div on:click=on_delete_click>"Delete"</div>
+        </div>
+    }
+}
+
+/// Downloads the entries as a JSON file
+fn export(entries: &[HistoryEntry]) {
+    let value = JsValue::from_serde(entries).unwrap();
+    let json = JSON::stringify_with_replacer_and_space(&value, &JsValue::NULL, &2.into()).unwrap();
+
+    let mut options = BlobPropertyBag::new();
+    options.type_("application/json");
+    let blob = match Blob::new_with_str_sequence_and_options(&Array::of1(&json), &options) {
+        Ok(blob) => blob,
+        Err(error) => {
+            jserror!("Error exporting history: {}", error);
+            return;
+        }
+    };
+
+    download_blob(EXPORT_FILENAME, &blob);
+}
@@ -0,0 +1,808 @@
+use std::collections::BTreeMap;
+
+use leptos::*;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use tokio::{select, sync::mpsc};
+use wasm_bindgen::JsValue;
+use web_sys::{FileSystemDirectoryHandle, FileSystemFileHandle};
+
+use crate::{
+    components::{
+        controls::{Cancelled, Controls},
+        progress::{progress_view, Progress},
+        swarm::{fetch_from_seeder, Seeding},
+    },
+    compression::{decompress, negotiate, Codec},
+    crypto::Cipher,
+    files::read_slice,
+    idb::IdbError,
+    merkle,
+    partial::{transfer_key, PartialStore, PartialTransfer},
+    protocol::{
+        receive_any, receive_message, send_message, Ack, Chunk, Done, FileHeader, Link, Manifest,
+        Message, ProtocolError, Resend, Seeder, TransferRequest,
+    },
+    save::{pick_directory, pick_save_file, save_pickers_supported, zip_name, SaveTarget},
+    sink::Sink,
+    utils::{copy_to_clipboard, format_bytes, format_ordinal, jserror, to_hex},
+    zip::ZipWriter,
+};
+
+/// Times a chunk may fail verification before the transfer is abandoned
+const MAX_CHUNK_ATTEMPTS: u32 = 3;
+
+#[derive(Clone)]
+struct ReceivedFile {
+    index: u32,
+    path: String,
+    size: u64,
+    mime_type: String,
+    status: RwSignal<String>,
+    /// SHA-256 of the saved file, in hex
+    hash: RwSignal<Option<String>>,
+}
+
+/// Shown once the file list arrives, before anything is downloaded. Accepting picks where the
+/// files get saved, declining sends `None`
+#[derive(Clone)]
+struct AcceptPrompt {
+    file_count: usize,
+    total_size: u64,
+    note: Option<String>,
+    has_folders: bool,
+    /// File name offered in the save picker. The file's own name, or the archive's if there are
+    /// several
+    suggested_name: String,
+    choice_tx: mpsc::Sender<Option<SaveChoice>>,
+}
+
+/// Shown when part of a file was saved by an earlier attempt. The user's choice of whether to
+/// resume is sent through `choice_tx`
+#[derive(Clone)]
+struct ResumePrompt {
+    name: String,
+    received_chunks: u64,
+    chunk_count: u64,
+    choice_tx: mpsc::Sender<bool>,
+}
+
+#[derive(Clone)]
+enum SaveChoice {
+    File(FileSystemFileHandle),
+    Downloads,
+    /// Written to the picked file, or downloaded if there's none
+    Zip(Option<FileSystemFileHandle>),
+    Directory(FileSystemDirectoryHandle),
+}
+
+/// Files or text offered by the peer, from the accept prompt until they're saved
+#[derive(Clone, Copy)]
+pub(crate) struct Incoming {
+    pub status: RwSignal<String>,
+    files: RwSignal<Vec<ReceivedFile>>,
+    text: RwSignal<Option<String>>,
+    progress: Progress,
+    accept_prompt: RwSignal<Option<AcceptPrompt>>,
+    resume_prompt: RwSignal<Option<ResumePrompt>>,
+}
+
+pub(crate) enum ReceiveOutcome {
+    Received,
+    Declined,
+}
+
+/// What every file of an accepted offer is received with
+struct Receiving {
+    store: PartialStore,
+    target: SaveTarget,
+    codec: Codec,
+    incoming: Incoming,
+    /// Set when the share has a swarm and this page takes part in it
+    seeding: Option<Seeding>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ReceiveFileError {
+    #[error("Error while receiving file data: {0}")]
+    ReceiveChunkError(ProtocolError),
+    #[error("Expected {0} but received a different message")]
+    UnexpectedMessage(&'static str),
+    #[error("Received chunk {received} of file {file} but expected chunk {expected} of file {expected_file}")]
+    OutOfOrderChunk {
+        file: u32,
+        received: u64,
+        expected_file: u32,
+        expected: u64,
+    },
+    #[error("Chunk {index} of {path} failed verification {MAX_CHUNK_ATTEMPTS} times")]
+    CorruptChunk { path: String, index: u64 },
+    #[error("{0} doesn't match the sender's hash")]
+    HashMismatch(String),
+    #[error("Error while reading saved chunks of {0}")]
+    ReadChunkError(String),
+    #[error("Error while accessing saved chunks: {0}")]
+    StorageError(IdbError),
+    #[error("Accept prompt closed unexpectedly")]
+    AcceptPromptClosed,
+    #[error("Resume prompt closed unexpectedly")]
+    ResumePromptClosed,
+    #[error("Error while saving file: {0:?}")]
+    SaveError(JsValue),
+    #[error("{0}")]
+    Cancelled(Cancelled),
+}
+
+impl Incoming {
+    pub fn new() -> Incoming {
+        Incoming {
+            status: create_rw_signal("Offered".to_string()),
+            files: create_rw_signal(Vec::new()),
+            text: create_rw_signal(None),
+            progress: Progress::new(0),
+            accept_prompt: create_rw_signal(None),
+            resume_prompt: create_rw_signal(None),
+        }
+    }
+}
+
+pub(crate) fn incoming_view(incoming: Incoming) -> impl IntoView {
+    let Incoming {
+        status,
+        files,
+        text,
+        progress,
+        accept_prompt,
+        resume_prompt,
+    } = incoming;
+
+    let accept_prompt_view = move || {
+        let prompt = accept_prompt.get()?;
+
+        let summary = match prompt.file_count {
+            1 => format!("1 file, {}", format_bytes(prompt.total_size)),
+            count => format!("{count} files, {}", format_bytes(prompt.total_size)),
+        };
+        let choose = {
+            let choice_tx = prompt.choice_tx.clone();
+            move |choice: Option<SaveChoice>| {
+                let _ = choice_tx.try_send(choice);
+                accept_prompt.set(None);
+            }
+        };
+
+        // Pickers need the click's user activation, so they're opened here rather than once the
+        // choice reaches the transfer
+        let save_as_view = save_pickers_supported().then(|| {
+            let choose = choose.clone();
+            let suggested_name = prompt.suggested_name.clone();
+            let is_single_file = prompt.file_count == 1;
+            let on_save_as_click = move |_| {
+                let choose = choose.clone();
+                let suggested_name = suggested_name.clone();
+                spawn_local(async move {
+                    match pick_save_file(&suggested_name).await {
+                        Ok(file) if is_single_file => choose(Some(SaveChoice::File(file))),
+                        Ok(file) => choose(Some(SaveChoice::Zip(Some(file)))),
+                        Err(error) => jserror!("Error picking file: {}", error),
+                    }
+                });
+            };
+            let text = if is_single_file {
+                "Save as..."
+            } else {
+                "Save as ZIP"
+            };
+
+            view! { <div on:click=on_save_as_click>{text}</div> }
+        });
+
+        let directory_view = (save_pickers_supported() && prompt.file_count > 1).then(|| {
+            let choose = choose.clone();
+            let on_directory_click = move |_| {
+                let choose = choose.clone();
+                spawn_local(async move {
+                    match pick_directory().await {
+                        Ok(directory) => choose(Some(SaveChoice::Directory(directory))),
+                        Err(error) => jserror!("Error picking folder: {}", error),
+                    }
+                });
+            };
+
+            view! { <div on:click=on_directory_click>"Save into a folder"</div> }
+        });
+
+        // Separate downloads would lose the folder structure, so those are zipped instead
+        let download_view = {
+            let choose = choose.clone();
+            let (choice, text) = if prompt.has_folders {
+                (SaveChoice::Zip(None), "Download as ZIP")
+            } else {
+                (SaveChoice::Downloads, "Download")
+            };
+            let on_download_click = move |_| choose(Some(choice.clone()));
+
+            view! { <div on:click=on_download_click>{text}</div> }
+        };
+
+        let on_decline_click = move |_| choose(None);
+
+        Some(view! {
+            <div>{summary}</div>
+            {prompt.note.map(|note| view! { <div>{format!("Note from the sender: {note}")}</div> })}
+            {save_as_view}
+            {directory_view}
+            {download_view}
+            <div on:click=on_decline_click>"Decline"</div>
+        })
+    };
+
+    let resume_prompt_view = move || {
+        let prompt = resume_prompt.get()?;
+
+        let percentage = prompt.received_chunks * 100 / prompt.chunk_count.max(1);
+        let choice_tx = prompt.choice_tx.clone();
+        let on_resume_click = move |_| {
+            let _ = prompt.choice_tx.try_send(true);
+            resume_prompt.set(None);
+        };
+        let on_restart_click = move |_| {
+            let _ = choice_tx.try_send(false);
+            resume_prompt.set(None);
+        };
+
+        Some(view! {
+            <div>{format!("{percentage}% of {} was saved by an earlier attempt", prompt.name)}</div>
+            <div on:click=on_resume_click>"Resume"</div>
+            <div on:click=on_restart_click>"Start over"</div>
+        })
+    };
+
+    let payload_view = move || match text.get() {
+        Some(text) => text_view(text).into_view(),
+        None => progress_view(progress).into_view(),
+    };
+
+    view! {
+        <div>
+            <div>{move || status.get()}</div>
+            {payload_view}
+            {accept_prompt_view}
+            {resume_prompt_view}
+            <For
+                each=move || files.get()
+                key=|file| file.index
+                children=received_file_view
+            />
+        </div>
+    }
+}
+
+fn text_view(text: String) -> impl IntoView {
+    let copied = create_rw_signal(false);
+    let on_copy_click = {
+        let text = text.clone();
+        move |_| {
+            let text = text.clone();
+            spawn_local(async move {
+                match copy_to_clipboard(&text).await {
+                    Ok(()) => copied.set(true),
+                    Err(error) => jserror!("Error copying text: {}", error),
+                }
+            });
+        }
+    };
+
+    view! {
+        <pre>{text}</pre>
+        <div on:click=on_copy_click>{move || if copied.get() { "Copied" } else { "Copy" }}</div>
+    }
+}
+
+fn received_file_view(file: ReceivedFile) -> impl IntoView {
+    view! {
+        <div>
+            <div>{format!("{} ({}, {})", file.path, format_bytes(file.size), file.mime_type)}</div>
+            <div>{move || file.status.get()}</div>
+            <div>{move || file.hash.get().map(|hash| format!("SHA-256 {hash}"))}</div>
+        </div>
+    }
+}
+
+/// Asks the user whether to accept the offered files, then receives them. Declining is not an
+/// error, the peer is told and the session carries on
+pub(crate) async fn receive_files(
+    link: &mut Link,
+    cipher: &Cipher,
+    manifest: Manifest,
+    incoming: Incoming,
+    controls: &mut Controls,
+) -> Result<ReceiveOutcome, ReceiveFileError> {
+    if let Some(text) = manifest.text {
+        incoming.text.set(Some(text));
+        incoming.status.set("Received text".to_string());
+        return Ok(ReceiveOutcome::Received);
+    }
+
+    let received_files = manifest
+        .files
+        .iter()
+        .zip(0..)
+        .map(|(header, index)| ReceivedFile {
+            index,
+            path: header.path.clone(),
+            size: header.size,
+            mime_type: header.mime_type.clone(),
+            status: create_rw_signal("Waiting".to_string()),
+            hash: create_rw_signal(None),
+        })
+        .collect::<Vec<_>>();
+    incoming.files.set(received_files.clone());
+
+    let total_size = manifest.files.iter().map(|file| file.size).sum();
+    incoming.progress.total.set(total_size);
+
+    let has_folders = manifest.files.iter().any(|file| file.path.contains('/'));
+    let zip_name = zip_name(manifest.files.iter().map(|file| file.path.as_str()));
+    let suggested_name = match manifest.files.as_slice() {
+        [file] => file.path.clone(),
+        _ => zip_name.clone(),
+    };
+
+    let Some(choice) = ask_accept(
+        incoming,
+        manifest.files.len(),
+        total_size,
+        manifest.note.clone(),
+        has_folders,
+        suggested_name,
+    )
+    .await?
+    else {
+        send_message(link, cipher, &Message::Decline);
+        incoming.status.set("Declined".to_string());
+        return Ok(ReceiveOutcome::Declined);
+    };
+
+    let target = match choice {
+        SaveChoice::File(file) => SaveTarget::File(file),
+        SaveChoice::Downloads => SaveTarget::Downloads,
+        SaveChoice::Zip(file) => {
+            let sink = match file {
+                Some(file) => Sink::writable(&file)
+                    .await
+                    .map_err(ReceiveFileError::SaveError)?,
+                None => Sink::download(&zip_name, None, "application/zip").await,
+            };
+            SaveTarget::Zip(ZipWriter::new(sink))
+        }
+        SaveChoice::Directory(directory) => SaveTarget::Directory(directory),
+    };
+
+    let codec = negotiate(&manifest.codecs);
+    info!("Using {codec:?} compression");
+
+    // Only pages that were set up to pass chunks on can fetch them from other receivers
+    let seeding = manifest.swarm_key.as_ref().and_then(|key| {
+        let seeding = use_context::<Seeding>()?;
+        seeding.join(key);
+        Some(seeding)
+    });
+
+    let store = PartialStore::open()
+        .await
+        .map_err(ReceiveFileError::StorageError)?;
+    let mut receiving = Receiving {
+        store,
+        target,
+        codec,
+        incoming,
+        seeding,
+    };
+
+    let file_count = manifest.files.len();
+    let mut saved = Vec::with_capacity(file_count);
+    for (header, received_file) in manifest.files.iter().zip(received_files) {
+        incoming.status.set(format!(
+            "Receiving files ({}/{file_count})",
+            received_file.index + 1
+        ));
+
+        let done = receive_one_file(
+            link,
+            cipher,
+            &mut receiving,
+            header,
+            &received_file,
+            controls,
+        )
+        .await?;
+        saved.push(done);
+    }
+
+    receiving
+        .target
+        .finish()
+        .await
+        .map_err(ReceiveFileError::SaveError)?;
+    // Only confirmed once everything's written, so the sender doesn't count a download that
+    // failed at the last step
+    for done in saved {
+        send_message(link, cipher, &Message::Done(done));
+    }
+
+    incoming.status.set(match file_count {
+        1 => "Saved 1 file".to_string(),
+        count => format!("Saved {count} files"),
+    });
+
+    Ok(ReceiveOutcome::Received)
+}
+
+async fn receive_one_file(
+    link: &mut Link,
+    cipher: &Cipher,
+    receiving: &mut Receiving,
+    header: &FileHeader,
+    received_file: &ReceivedFile,
+    controls: &mut Controls,
+) -> Result<Done, ReceiveFileError> {
+    let Receiving {
+        store,
+        target,
+        codec,
+        incoming,
+        seeding,
+    } = receiving;
+    let status = received_file.status;
+    let key = transfer_key(header);
+
+    let saved_transfer = store
+        .get(&key)
+        .await
+        .map_err(ReceiveFileError::StorageError)?;
+    let mut transfer = match saved_transfer {
+        Some(transfer) if transfer.received_chunks > 0 && !transfer.seeding => {
+            status.set("Found partial download".to_string());
+            if ask_resume(*incoming, &transfer).await? {
+                transfer
+            } else {
+                store
+                    .remove(&key)
+                    .await
+                    .map_err(ReceiveFileError::StorageError)?;
+                PartialTransfer::new(header)
+            }
+        }
+        _ => PartialTransfer::new(header),
+    };
+
+    let progress = incoming.progress;
+    progress.skip(header.bytes_before(transfer.received_chunks));
+
+    // In a swarm, chunks are fetched from receivers further ahead for as long as there are any.
+    // They're saved like the sender's, so they're written out with the chunks saved earlier
+    if let Some(seeding) = seeding {
+        seeding.keep(&key);
+        loop {
+            let from_chunk = transfer.received_chunks;
+            let seeder =
+                ask_for_seeder(link, cipher, received_file.index, from_chunk, controls).await?;
+            if seeder.peer_id.is_none() {
+                break;
+            }
+
+            status.set("Fetching from another receiver".to_string());
+            if let Err(error) =
+                fetch_from_seeder(seeding, &seeder, header, store, &mut transfer, progress).await
+            {
+                warn!(
+                    "Error fetching {} from another receiver: {error}",
+                    header.path
+                );
+            }
+            // A seeder that failed straight away isn't asked again, the rest comes from the sender
+            if transfer.received_chunks == from_chunk {
+                break;
+            }
+        }
+    }
+
+    let mut writer = target
+        .create(header)
+        .await
+        .map_err(ReceiveFileError::SaveError)?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+
+    // Chunks saved by an earlier attempt are written out first, so the file is complete
+    if transfer.received_chunks > 0 {
+        status.set("Writing saved chunks".to_string());
+    }
+    for index in 0..transfer.received_chunks {
+        let chunk = store
+            .load_chunk(&key, index)
+            .await
+            .map_err(ReceiveFileError::StorageError)?;
+        let data = read_slice(&chunk, 0, chunk.size() as u64)
+            .await
+            .map_err(|error| {
+                jserror!("Error reading saved chunk: {}", error);
+                ReceiveFileError::ReadChunkError(header.path.clone())
+            })?;
+        hasher.update(&data);
+        size += data.len() as u64;
+        writer
+            .write(&data)
+            .await
+            .map_err(ReceiveFileError::SaveError)?;
+    }
+
+    send_message(
+        link,
+        cipher,
+        &Message::Request(TransferRequest {
+            file: received_file.index,
+            from_chunk: transfer.received_chunks,
+            codec: *codec,
+            seeding: seeding.is_some(),
+            find_seeder: false,
+        }),
+    );
+
+    status.set("Receiving".to_string());
+
+    // Chunks are written out as they arrive. They're also kept in IndexedDB until the file is
+    // complete, so the transfer can be resumed
+    let mut expected = transfer.received_chunks;
+    let mut attempts = 0;
+    // Set while the sender has us waiting in its upload queue
+    let mut queued = false;
+    // Chunks spread across several connections arrive out of order, so the ones ahead of the
+    // expected chunk wait here
+    let mut ahead = BTreeMap::<u64, Chunk>::new();
+    let window = link.ack_window();
+    while expected < header.chunk_count {
+        let chunk = match ahead.remove(&expected) {
+            Some(chunk) => chunk,
+            None => {
+                let message = select! {
+                    Some(control) = controls.recv() => {
+                        controls
+                            .apply(link, cipher, control)
+                            .map_err(ReceiveFileError::Cancelled)?;
+                        continue;
+                    }
+                    message = receive_any(link, cipher) => message,
+                };
+                let chunk = match message.map_err(ReceiveFileError::ReceiveChunkError)? {
+                    Message::Chunk(chunk) if queued => {
+                        queued = false;
+                        status.set("Receiving".to_string());
+                        chunk
+                    }
+                    Message::Chunk(chunk) => chunk,
+                    Message::Pause => {
+                        controls.peer_paused(true);
+                        continue;
+                    }
+                    Message::Resume => {
+                        controls.peer_paused(false);
+                        continue;
+                    }
+                    // The sender is busy with other receivers
+                    Message::Queued(position) => {
+                        queued = true;
+                        status.set(format!("Waiting in queue ({})", format_ordinal(position)));
+                        continue;
+                    }
+                    _ => return Err(ReceiveFileError::UnexpectedMessage("file data")),
+                };
+                // Copies of chunks that were already saved, sent again after a failed one
+                if chunk.file < received_file.index
+                    || (chunk.file == received_file.index && chunk.index < expected)
+                {
+                    continue;
+                }
+                if chunk.file != received_file.index || chunk.index >= expected + window {
+                    return Err(ReceiveFileError::OutOfOrderChunk {
+                        file: chunk.file,
+                        received: chunk.index,
+                        expected_file: received_file.index,
+                        expected,
+                    });
+                }
+                if chunk.index > expected {
+                    ahead.insert(chunk.index, chunk);
+                    continue;
+                }
+                chunk
+            }
+        };
+
+        // Chunks that don't decompress are treated like any other corrupt chunk
+        let chunk_len = header.chunk_len(chunk.index) as usize;
+        let data = decompress(chunk.codec, &chunk.data, chunk_len)
+            .ok()
+            .filter(|data| {
+                merkle::verify(
+                    &header.merkle_root,
+                    header.chunk_count,
+                    chunk.index,
+                    data,
+                    &chunk.proof,
+                )
+            });
+        let Some(data) = data else {
+            attempts += 1;
+            if attempts >= MAX_CHUNK_ATTEMPTS {
+                return Err(ReceiveFileError::CorruptChunk {
+                    path: header.path.clone(),
+                    index: chunk.index,
+                });
+            }
+
+            warn!(
+                "Chunk {} of {} failed verification",
+                chunk.index, header.path
+            );
+            send_message(
+                link,
+                cipher,
+                &Message::Resend(Resend {
+                    file: chunk.file,
+                    index: chunk.index,
+                }),
+            );
+            continue;
+        };
+        attempts = 0;
+
+        store
+            .put_chunk(&mut transfer, chunk.index, &data, &chunk.proof)
+            .await
+            .map_err(ReceiveFileError::StorageError)?;
+        hasher.update(&data);
+        size += data.len() as u64;
+        writer
+            .write(&data)
+            .await
+            .map_err(ReceiveFileError::SaveError)?;
+
+        send_message(
+            link,
+            cipher,
+            &Message::Ack(Ack {
+                file: chunk.file,
+                index: chunk.index,
+            }),
+        );
+        progress.advance(data.len() as u64);
+        progress.record_compression(data.len() as u64, chunk.data.len() as u64);
+        expected += 1;
+    }
+
+    info!("Received {}, {} bytes", header.path, header.size);
+
+    let hash: [u8; 32] = hasher.finalize().into();
+    if hash != header.hash {
+        // Dropping the writer throws away what was written
+        drop(writer);
+        if let Some(seeding) = seeding {
+            seeding.release(&key);
+        }
+        store
+            .remove(&key)
+            .await
+            .map_err(ReceiveFileError::StorageError)?;
+        return Err(ReceiveFileError::HashMismatch(header.path.clone()));
+    }
+
+    status.set("Saving".to_string());
+    writer.close().await.map_err(ReceiveFileError::SaveError)?;
+
+    // Chunks passed on to other receivers are kept until the page stops seeding
+    match seeding {
+        Some(seeding) => store.mark_seeding(&mut transfer, &seeding.owner()).await,
+        None => store.remove(&key).await,
+    }
+    .map_err(ReceiveFileError::StorageError)?;
+
+    status.set("Saved".to_string());
+    received_file.hash.set(Some(to_hex(&hash)));
+
+    Ok(Done {
+        file: received_file.index,
+        size,
+        hash,
+    })
+}
+
+/// Asks the sender which other receiver to fetch the chunks from `from_chunk` on from
+async fn ask_for_seeder(
+    link: &mut Link,
+    cipher: &Cipher,
+    file: u32,
+    from_chunk: u64,
+    controls: &mut Controls,
+) -> Result<Seeder, ReceiveFileError> {
+    send_message(
+        link,
+        cipher,
+        &Message::Request(TransferRequest {
+            file,
+            from_chunk,
+            codec: Codec::None,
+            seeding: true,
+            find_seeder: true,
+        }),
+    );
+
+    loop {
+        let message = select! {
+            Some(control) = controls.recv() => {
+                controls
+                    .apply(link, cipher, control)
+                    .map_err(ReceiveFileError::Cancelled)?;
+                continue;
+            }
+            message = receive_message(link, cipher) => message,
+        };
+        match message.map_err(ReceiveFileError::ReceiveChunkError)? {
+            Message::Seeder(seeder) if seeder.file == file && seeder.from_chunk == from_chunk => {
+                return Ok(seeder);
+            }
+            Message::Pause => controls.peer_paused(true),
+            Message::Resume => controls.peer_paused(false),
+            _ => return Err(ReceiveFileError::UnexpectedMessage("seeder")),
+        }
+    }
+}
+
+async fn ask_accept(
+    incoming: Incoming,
+    file_count: usize,
+    total_size: u64,
+    note: Option<String>,
+    has_folders: bool,
+    suggested_name: String,
+) -> Result<Option<SaveChoice>, ReceiveFileError> {
+    let (choice_tx, mut choice_rx) = mpsc::channel(1);
+
+    incoming.accept_prompt.set(Some(AcceptPrompt {
+        file_count,
+        total_size,
+        note,
+        has_folders,
+        suggested_name,
+        choice_tx,
+    }));
+
+    incoming
+        .status
+        .set("Accept or decline the files".to_string());
+
+    choice_rx
+        .recv()
+        .await
+        .ok_or(ReceiveFileError::AcceptPromptClosed)
+}
+
+async fn ask_resume(
+    incoming: Incoming,
+    transfer: &PartialTransfer,
+) -> Result<bool, ReceiveFileError> {
+    let (choice_tx, mut choice_rx) = mpsc::channel(1);
+
+    incoming.resume_prompt.set(Some(ResumePrompt {
+        name: transfer.path.clone(),
+        received_chunks: transfer.received_chunks,
+        chunk_count: transfer.chunk_count,
+        choice_tx,
+    }));
+
+    choice_rx
+        .recv()
+        .await
+        .ok_or(ReceiveFileError::ResumePromptClosed)
+}
@@ -1,12 +1,20 @@
-use leptos::{html::Input, *};
+use leptos::{
+    html::{Input, Textarea},
+    *,
+};
 use leptos_meta::Title;
 use leptos_router::NavigateOptions;
 use log::error;
-use web_sys::{Event, MouseEvent};
+use web_sys::{DragEvent, Event, MouseEvent};
 
 use crate::{
-    components::{app::FileToSend, settings::SettingsEditor},
-    peerjs::peerid::PeerID,
+    components::{
+        app::{FileToSend, TextToSend},
+        settings::SettingsEditor,
+    },
+    crypto::parse_share_code,
+    files::{self, SelectedFile},
+    utils::jserror,
 };
 
 #[component]
@@ -14,9 +22,12 @@ pub(crate) fn Menu() -> impl IntoView {
     let navigate = leptos_router::use_navigate();
 
     let set_file_to_send = use_context::<WriteSignal<FileToSend>>().unwrap();
+    let set_text_to_send = use_context::<WriteSignal<TextToSend>>().unwrap();
 
     let file_input_ref = create_node_ref::<Input>();
+    let folder_input_ref = create_node_ref::<Input>();
     let receive_input_ref = create_node_ref::<Input>();
+    let text_input_ref = create_node_ref::<Textarea>();
 
     let send_click = move |_: MouseEvent| {
         if let Some(e) = file_input_ref() {
@@ -27,51 +38,113 @@ pub(crate) fn Menu() -> impl IntoView {
         }
     };
 
+    let send_folder_click = move |_: MouseEvent| {
+        if let Some(e) = folder_input_ref() {
+            spawn_local(async move {
+                e.click();
+            });
+        }
+    };
+
     let navigate_ = navigate.clone();
-    let on_hidden_input_change = move |_: Event| {
-        let Some(file) = file_input_ref()
-            .and_then(|e| e.files())
-            .filter(|fl| fl.length() >= 1)
-            .and_then(|fl| fl.item(0))
-        else {
+    let send_text_click = move |_: MouseEvent| {
+        let Some(text) = text_input_ref().map(|e| e.value()) else {
+            error!("No text input node ref");
             return;
         };
+        if text.trim().is_empty() {
+            return;
+        }
 
-        set_file_to_send(FileToSend(Some(file)));
+        set_text_to_send(TextToSend(Some(text)));
         navigate_("/send", NavigateOptions::default());
     };
 
+    let navigate_ = navigate.clone();
+    let send_files = move |files: Vec<SelectedFile>| {
+        if files.is_empty() {
+            return;
+        }
+
+        set_file_to_send(FileToSend(files));
+        navigate_("/send", NavigateOptions::default());
+    };
+
+    let send_files_ = send_files.clone();
+    let on_hidden_input_change = move |_: Event| {
+        let Some(file_list) = file_input_ref().and_then(|e| e.files()) else {
+            return;
+        };
+
+        send_files_(files::from_file_list(&file_list));
+    };
+
+    let send_files_ = send_files.clone();
+    let on_hidden_folder_input_change = move |_: Event| {
+        let Some(file_list) = folder_input_ref().and_then(|e| e.files()) else {
+            return;
+        };
+
+        send_files_(files::from_file_list(&file_list));
+    };
+
+    let on_drop = move |event: DragEvent| {
+        event.prevent_default();
+
+        let Some(data_transfer) = event.data_transfer() else {
+            return;
+        };
+        let entries = files::dropped_entries(&data_transfer);
+
+        let send_files = send_files.clone();
+        spawn_local(async move {
+            match files::from_entries(entries).await {
+                Ok(files) => send_files(files),
+                Err(error) => jserror!("Error reading dropped files: {}", error),
+            }
+        });
+    };
+
+    let navigate_ = navigate.clone();
+    let history_click = move |_: MouseEvent| navigate_("/history", NavigateOptions::default());
+
     let on_receive_input_change = move |_| {
-        let Some(peer_id_string) = receive_input_ref().map(|e| e.value()) else {
+        let Some(input) = receive_input_ref().map(|e| e.value()) else {
             error!("No input node ref");
             return;
         };
 
-        let Some(peer_id) = PeerID::new_short_id(peer_id_string) else {
-            error!("Invalid peer id");
+        // Accept either the code or the whole sharing link
+        let code = input.rsplit('#').next().unwrap().trim();
+        if parse_share_code(code).is_none() {
+            error!("Invalid share code");
             return;
-        };
+        }
 
-        navigate(
-            &format!("/receive/{}", peer_id.base()),
-            NavigateOptions::default(),
-        );
+        navigate(&format!("/receive/{code}"), NavigateOptions::default());
     };
 
     view! {
-        <div class="menu-container">
+        <div class="menu-container" on:dragover=|event: DragEvent| event.prevent_default() on:drop=on_drop>
             <Title text="Menu"/>
             <div class="menu">
-                <div>"Peer-to-peer file transfer. Select a file to send, or enter another user's code to receive. All data is sent encrypted thanks to WebRTC. Connections brokered via PeerJS's Cloud PeerServer."</div>
-                <div class="menu-send" on:click=send_click>"Send file"</div>
+                <div>"Peer-to-peer file transfer. Select or drop files or a folder to send, or enter another user's code or link to receive. All data is end-to-end encrypted with a key that only travels in the link, or one agreed through the share's password. Connections brokered via PeerJS's Cloud PeerServer."</div>
+                <div class="menu-send" on:click=send_click>"Send files"</div>
+                <div class="menu-send" on:click=send_folder_click>"Send folder"</div>
+                <div class="menu-send-text">
+                    <textarea class="menu-send-text-input" placeholder="Text, a link or a token" node_ref=text_input_ref></textarea>
+                    <div class="menu-send" on:click=send_text_click>"Send text"</div>
+                </div>
                 <div class="menu-receive">
                     <div class="menu-receive-text">"Receive from"</div>
                     <input class="menu-receive-input" type="text" on:change=on_receive_input_change node_ref=receive_input_ref></input>
                 </div>
+                <div class="menu-send" on:click=history_click>"History"</div>
                 <div class="menu-separator"/>
                 <SettingsEditor/>
             </div>
-            <input type="file" class="menu-hidden-file-input" node_ref=file_input_ref on:change=on_hidden_input_change/>
+            <input type="file" multiple class="menu-hidden-file-input" node_ref=file_input_ref on:change=on_hidden_input_change/>
+            <input type="file" webkitdirectory class="menu-hidden-file-input" node_ref=folder_input_ref on:change=on_hidden_folder_input_change/>
         </div>
     }
 }
@@ -1,7 +1,14 @@
 pub(crate) mod app;
+mod controls;
 mod footer;
 mod header;
+mod history;
+mod incoming;
 mod menu;
+mod outgoing;
+mod progress;
 mod receive;
 mod send;
+mod session;
 mod settings;
+mod swarm;
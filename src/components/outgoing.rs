@@ -0,0 +1,527 @@
+use std::{collections::VecDeque, rc::Rc};
+
+use leptos::*;
+use log::warn;
+use tokio::{select, sync::oneshot};
+use wasm_bindgen::JsValue;
+use web_sys::File;
+
+use crate::{
+    components::{
+        controls::{Cancelled, Controls},
+        progress::{progress_view, Progress},
+        swarm::SwarmMember,
+    },
+    compression::{compress, is_compressible, Codec, SUPPORTED_CODECS},
+    crypto::Cipher,
+    files::{read_slice, SelectedFile},
+    merkle::{hash_blob, MerkleTree},
+    peerjs::dataconnection::DataConnectionError,
+    protocol::{
+        receive_message, send_chunk, send_message, Chunk, FileHeader, Link, Manifest, Message,
+        ProtocolError, Seeder, CHUNK_SIZE,
+    },
+    queue::{QueueTicket, UploadQueue},
+    utils::{format_bytes, format_ordinal, jserror, to_hex},
+};
+
+/// A file being shared, with the header sent to peers in the manifest
+pub(crate) struct SharedFile {
+    file: File,
+    pub header: FileHeader,
+    tree: MerkleTree,
+}
+
+/// What's shared with the peer
+#[derive(Clone)]
+pub(crate) enum Payload {
+    Files(Rc<Vec<SharedFile>>),
+    Text(Rc<String>),
+}
+
+/// Files or text to offer the peer
+pub(crate) struct Offer {
+    pub payload: Payload,
+    pub note: Option<String>,
+    pub outgoing: Outgoing,
+    /// Set once the manifest has been sent, so it isn't sent again after giving way
+    pub offered: bool,
+    /// Upload queue the files wait their turn in once the peer accepts them
+    pub queue: Option<UploadQueue>,
+    /// Set when the peer may fetch chunks from other receivers of the share, and pass on its own
+    pub swarm: Option<SwarmMember>,
+    /// Told once the peer has everything
+    pub sent_tx: Option<oneshot::Sender<()>>,
+}
+
+/// Files offered to the peer, from the offer until they're sent
+#[derive(Clone, Copy)]
+pub(crate) struct Outgoing {
+    pub status: RwSignal<String>,
+    progress: Progress,
+    headers: RwSignal<Vec<FileHeader>>,
+    text: RwSignal<Option<String>>,
+}
+
+/// How an offer ended, when it didn't fail
+pub(crate) enum SendOutcome {
+    Sent,
+    Declined,
+    /// The peer offered files at the same time, and ours gave way. Theirs is received first, then
+    /// the reply to ours is waited for again
+    GaveWay(Manifest),
+}
+
+/// What the receiver said about a chunk
+enum ChunkReply {
+    Ack,
+    Resend,
+    /// The receiver paused or resumed the transfer
+    Paused(bool),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Error while hashing {0}")]
+pub(crate) struct HashFileError(String);
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum SendFileError {
+    #[error("Error while receiving transfer request: {0}")]
+    ReceiveRequestError(ProtocolError),
+    #[error("Expected {0} but received a different message")]
+    UnexpectedMessage(&'static str),
+    #[error("Peer requested chunk {chunk} of file {file}, which doesn't exist")]
+    InvalidRequest { file: u32, chunk: u64 },
+    #[error("Error while receiving acknowledgement: {0}")]
+    ReceiveAckError(ProtocolError),
+    #[error("Peer replied about chunk {chunk} of file {file}, which wasn't expected")]
+    UnexpectedReply { file: u32, chunk: u64 },
+    #[error("Error while reading file")]
+    ReadFileError,
+    #[error("Error while sending file data: {0}")]
+    SendChunkError(DataConnectionError),
+    #[error("Error while waiting for the peer to confirm: {0}")]
+    ReceiveDoneError(ProtocolError),
+    #[error("Receiver disconnected before confirming")]
+    NotConfirmed,
+    #[error("Receiver saved {0} with a different size or hash than was sent")]
+    SavedMismatch(String),
+    #[error("{0}")]
+    Cancelled(Cancelled),
+}
+
+impl Outgoing {
+    pub fn new(status: &str) -> Outgoing {
+        Outgoing {
+            status: create_rw_signal(status.to_string()),
+            progress: Progress::new(0),
+            headers: create_rw_signal(Vec::new()),
+            text: create_rw_signal(None),
+        }
+    }
+
+    /// Shows what's offered once it's ready
+    pub fn set_payload(&self, payload: &Payload) {
+        match payload {
+            Payload::Files(files) => {
+                self.progress
+                    .total
+                    .set(files.iter().map(|file| file.header.size).sum());
+                self.headers
+                    .set(files.iter().map(|file| file.header.clone()).collect());
+            }
+            Payload::Text(text) => self.text.set(Some(text.to_string())),
+        }
+    }
+}
+
+pub(crate) fn outgoing_view(outgoing: Outgoing) -> impl IntoView {
+    let Outgoing {
+        status,
+        progress,
+        headers,
+        text,
+    } = outgoing;
+
+    let payload_view = move || match text.get() {
+        Some(text) => view! { <pre>{text}</pre> }.into_view(),
+        None => progress_view(progress).into_view(),
+    };
+
+    view! {
+        <div>
+            <div>{move || status.get()}</div>
+            {payload_view}
+            <For
+                each=move || headers.get()
+                key=|header| header.path.clone()
+                children=header_view
+            />
+        </div>
+    }
+}
+
+pub(crate) fn header_view(header: FileHeader) -> impl IntoView {
+    view! {
+        <div>
+            <div>{format!("{} ({})", header.path, format_bytes(header.size))}</div>
+            <div>{format!("SHA-256 {}", to_hex(&header.hash))}</div>
+        </div>
+    }
+}
+
+/// Hashes the files so they can be offered. `on_file` is called with each file's path and
+/// position before it's hashed
+pub(crate) async fn share_files(
+    files: Vec<SelectedFile>,
+    on_file: impl Fn(&str, usize),
+) -> Result<Vec<SharedFile>, HashFileError> {
+    let mut shared_files = Vec::with_capacity(files.len());
+    for (i, SelectedFile { file, path }) in files.into_iter().enumerate() {
+        on_file(&path, i);
+
+        let (hash, tree) = hash_blob(&file).await.map_err(|error| {
+            jserror!("Error hashing file: {}", error);
+            HashFileError(path.clone())
+        })?;
+        let header = FileHeader::new(path, file.size() as u64, file.type_(), hash, tree.root());
+        shared_files.push(SharedFile { file, header, tree });
+    }
+
+    Ok(shared_files)
+}
+
+/// Offers the files, then sends each one the peer requests. If the peer's offer crosses ours,
+/// ours gives way when `give_way` is set. Otherwise the peer's is put in `crossed`, to be received
+/// once ours is done. No chunks are sent while either peer has paused
+pub(crate) async fn send_files(
+    link: &mut Link,
+    cipher: &Cipher,
+    offer: &Offer,
+    give_way: bool,
+    crossed: &mut Option<Manifest>,
+    controls: &mut Controls,
+) -> Result<SendOutcome, SendFileError> {
+    let Offer {
+        payload,
+        note,
+        outgoing,
+        offered,
+        queue,
+        swarm,
+        ..
+    } = offer;
+    let Outgoing {
+        status, progress, ..
+    } = *outgoing;
+    let (files, text) = match payload {
+        Payload::Files(files) => (files.as_slice(), None),
+        Payload::Text(text) => ([].as_slice(), Some(text.to_string())),
+    };
+
+    if !offered {
+        let manifest = Manifest {
+            files: files.iter().map(|file| file.header.clone()).collect(),
+            text,
+            note: note.clone(),
+            codecs: SUPPORTED_CODECS.to_vec(),
+            swarm_key: swarm.as_ref().map(SwarmMember::key),
+        };
+        send_message(link, cipher, &Message::Manifest(manifest));
+    }
+    if let Payload::Text(_) = payload {
+        status.set("Sent text".to_string());
+        return Ok(SendOutcome::Sent);
+    }
+    status.set("Waiting for peer to accept".to_string());
+
+    // The seeder the peer was last pointed at, with the file and the chunk it fetches from. The
+    // seeder is credited with what it passed on once the peer asks for the rest
+    let mut pointed = None;
+    // Held until every file has been sent
+    let mut _ticket = None;
+
+    // The receiver requests each file in turn once it's ready for it. In a swarm, it first asks
+    // which other receiver it can fetch the file from
+    for requested in 0..files.len() {
+        let (request, shared_file) = loop {
+            let request = loop {
+                let message = select! {
+                    Some(control) = controls.recv() => {
+                        controls
+                            .apply(link, cipher, control)
+                            .map_err(SendFileError::Cancelled)?;
+                        continue;
+                    }
+                    message = receive_message(link, cipher) => message,
+                };
+                match message.map_err(SendFileError::ReceiveRequestError)? {
+                    Message::Request(request) => break request,
+                    Message::Pause => controls.peer_paused(true),
+                    Message::Resume => controls.peer_paused(false),
+                    Message::Decline => {
+                        status.set("Declined by peer".to_string());
+                        return Ok(SendOutcome::Declined);
+                    }
+                    // The peer's offer crossed ours, so one of the two has to give way
+                    Message::Manifest(manifest) if requested == 0 && crossed.is_none() => {
+                        if give_way {
+                            status.set("Waiting for peer".to_string());
+                            return Ok(SendOutcome::GaveWay(manifest));
+                        }
+                        *crossed = Some(manifest);
+                    }
+                    _ => return Err(SendFileError::UnexpectedMessage("transfer request")),
+                }
+            };
+            let invalid_request = SendFileError::InvalidRequest {
+                file: request.file,
+                chunk: request.from_chunk,
+            };
+            let Some(shared_file) = files.get(request.file as usize) else {
+                return Err(invalid_request);
+            };
+            let header = &shared_file.header;
+            if request.from_chunk > header.chunk_count
+                || (request.codec != Codec::None && !SUPPORTED_CODECS.contains(&request.codec))
+            {
+                return Err(invalid_request);
+            }
+
+            if let (Some(member), Some((file, seeder, from_chunk))) = (swarm, pointed.take()) {
+                if file == request.file && request.from_chunk > from_chunk {
+                    member.credit(
+                        &seeder,
+                        header.bytes_before(request.from_chunk) - header.bytes_before(from_chunk),
+                    );
+                }
+            }
+            if let Some(member) = swarm.as_ref().filter(|_| request.seeding) {
+                member.hold(request.file, request.from_chunk);
+            }
+            if !request.find_seeder {
+                break (request, shared_file);
+            }
+
+            let seeder = swarm
+                .as_ref()
+                .and_then(|member| member.find_seeder(request.file, request.from_chunk));
+            let (peer_id, to_chunk) = match seeder {
+                Some((peer_id, to_chunk)) => {
+                    status.set(format!(
+                        "Peer is fetching {} from another receiver",
+                        header.path
+                    ));
+                    pointed = Some((request.file, peer_id.clone(), request.from_chunk));
+                    (Some(peer_id), to_chunk)
+                }
+                None => (None, request.from_chunk),
+            };
+            send_message(
+                link,
+                cipher,
+                &Message::Seeder(Seeder {
+                    file: request.file,
+                    from_chunk: request.from_chunk,
+                    peer_id,
+                    to_chunk,
+                }),
+            );
+        };
+        // The upload slot is only taken once the peer has accepted, so an open prompt doesn't
+        // hold up the peers behind it
+        if let (0, Some(queue)) = (requested, queue) {
+            _ticket = Some(wait_for_turn(link, cipher, queue.join(), status, controls).await?);
+        }
+        let SharedFile { file, header, tree } = shared_file;
+        let codec = if is_compressible(&header.mime_type) {
+            request.codec
+        } else {
+            Codec::None
+        };
+
+        let position = format!("({}/{})", request.file + 1, files.len());
+        if request.from_chunk > 0 {
+            status.set(format!(
+                "Resuming {} from chunk {}/{} {position}",
+                header.path, request.from_chunk, header.chunk_count
+            ));
+        } else {
+            status.set(format!("Sending {} {position}", header.path));
+        }
+        progress.skip(header.bytes_before(request.from_chunk));
+
+        // Acknowledgements drive the progress, and stop the sender from getting too far ahead
+        let mut acknowledged = request.from_chunk;
+        let mut next = request.from_chunk;
+        // Compressed sizes of the chunks sent but not yet acknowledged, oldest first
+        let mut in_flight = VecDeque::new();
+        while acknowledged < header.chunk_count {
+            if !controls.is_paused()
+                && next < header.chunk_count
+                && next - acknowledged < link.ack_window()
+            {
+                let data = read_chunk(file, next)
+                    .await
+                    .map_err(|_| SendFileError::ReadFileError)?;
+                let (chunk_codec, data) = compress(codec, &data);
+                in_flight.push_back(data.len() as u64);
+                let chunk = Chunk {
+                    file: request.file,
+                    index: next,
+                    codec: chunk_codec,
+                    data,
+                    proof: tree.proof(next),
+                };
+                // Also waits for room on the connection, so a fast disk doesn't fill the
+                // browser's send buffer
+                send_chunk(link, cipher, chunk)
+                    .await
+                    .map_err(SendFileError::SendChunkError)?;
+                next += 1;
+                continue;
+            }
+
+            let reply = select! {
+                Some(control) = controls.recv() => {
+                    controls
+                        .apply(link, cipher, control)
+                        .map_err(SendFileError::Cancelled)?;
+                    continue;
+                }
+                reply = receive_reply(link, cipher, request.file, acknowledged, next) => reply?,
+            };
+            match reply {
+                ChunkReply::Ack => {
+                    let Some(compressed_len) = in_flight.pop_front() else {
+                        return Err(SendFileError::UnexpectedReply {
+                            file: request.file,
+                            chunk: acknowledged,
+                        });
+                    };
+                    let chunk_len = header.chunk_len(acknowledged);
+                    progress.advance(chunk_len);
+                    progress.record_compression(chunk_len, compressed_len);
+                    acknowledged += 1;
+                    if let Some(member) = swarm {
+                        member.count_sent(chunk_len);
+                        if request.seeding {
+                            member.hold(request.file, acknowledged);
+                        }
+                    }
+                }
+                ChunkReply::Resend => {
+                    warn!("Resending chunk {acknowledged} of {}", header.path);
+                    next = acknowledged;
+                    in_flight.clear();
+                }
+                ChunkReply::Paused(paused) => controls.peer_paused(paused),
+            }
+        }
+    }
+
+    // Only done once the receiver confirms what it saved matches what was sent. A receiver that
+    // crashed or was closed part way never confirms
+    status.set("Waiting for peer to confirm".to_string());
+    for (index, shared_file) in (0..).zip(files) {
+        let done = loop {
+            match receive_message(link, cipher).await {
+                Ok(Message::Done(done)) => break done,
+                Ok(Message::Pause) => controls.peer_paused(true),
+                Ok(Message::Resume) => controls.peer_paused(false),
+                Ok(_) => return Err(SendFileError::UnexpectedMessage("confirmation")),
+                Err(ProtocolError::DataConnectionError(DataConnectionError::Closed)) => {
+                    return Err(SendFileError::NotConfirmed);
+                }
+                Err(error) => return Err(SendFileError::ReceiveDoneError(error)),
+            }
+        };
+        let header = &shared_file.header;
+        if done.file != index || done.size != header.size || done.hash != header.hash {
+            return Err(SendFileError::SavedMismatch(header.path.clone()));
+        }
+    }
+
+    status.set(match files.len() {
+        1 => "Done, 1 file saved by peer".to_string(),
+        count => format!("Done, {count} files saved by peer"),
+    });
+
+    Ok(SendOutcome::Sent)
+}
+
+/// Waits for the ticket's turn in the upload queue, telling the peer its place as it moves up.
+/// Returns the ticket, which holds the upload slot until it's dropped
+async fn wait_for_turn(
+    link: &mut Link,
+    cipher: &Cipher,
+    mut ticket: QueueTicket,
+    status: RwSignal<String>,
+    controls: &mut Controls,
+) -> Result<QueueTicket, SendFileError> {
+    // The place in the queue last told to the peer
+    let mut queued = None;
+    while let Err(position) = ticket.try_take() {
+        if queued != Some(position) {
+            send_message(link, cipher, &Message::Queued(position));
+            status.set(format!("Waiting in queue ({})", format_ordinal(position)));
+            queued = Some(position);
+        }
+
+        select! {
+            Some(control) = controls.recv() => {
+                controls
+                    .apply(link, cipher, control)
+                    .map_err(SendFileError::Cancelled)?;
+            }
+            () = ticket.changed() => {}
+            message = receive_message(link, cipher) => {
+                match message.map_err(SendFileError::ReceiveRequestError)? {
+                    Message::Pause => controls.peer_paused(true),
+                    Message::Resume => controls.peer_paused(false),
+                    _ => return Err(SendFileError::UnexpectedMessage("no message while queued")),
+                }
+            }
+        }
+    }
+
+    Ok(ticket)
+}
+
+/// Receives the reply about the oldest unacknowledged chunk, or a pause or resume. Replies about
+/// chunks from `next` on are rejected, as they haven't been sent
+async fn receive_reply(
+    link: &mut Link,
+    cipher: &Cipher,
+    file: u32,
+    expected: u64,
+    next: u64,
+) -> Result<ChunkReply, SendFileError> {
+    let (reply, reply_file, index) = match receive_message(link, cipher)
+        .await
+        .map_err(SendFileError::ReceiveAckError)?
+    {
+        Message::Ack(ack) => (ChunkReply::Ack, ack.file, ack.index),
+        Message::Resend(resend) => (ChunkReply::Resend, resend.file, resend.index),
+        Message::Pause => return Ok(ChunkReply::Paused(true)),
+        Message::Resume => return Ok(ChunkReply::Paused(false)),
+        _ => return Err(SendFileError::UnexpectedMessage("acknowledgement")),
+    };
+    if reply_file != file || index != expected || index >= next {
+        return Err(SendFileError::UnexpectedReply {
+            file: reply_file,
+            chunk: index,
+        });
+    }
+
+    Ok(reply)
+}
+
+async fn read_chunk(file: &File, index: u64) -> Result<Vec<u8>, JsValue> {
+    let start = index * CHUNK_SIZE;
+    let end = (start + CHUNK_SIZE).min(file.size() as u64);
+
+    read_slice(file, start, end)
+        .await
+        .inspect_err(|error| jserror!("Error reading file: {}", error.clone()))
+}
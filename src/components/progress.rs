@@ -0,0 +1,171 @@
+use std::{collections::VecDeque, time::Duration};
+
+use js_sys::Date;
+use leptos::*;
+
+use crate::{
+    compression::format_ratio,
+    utils::{format_bytes, format_duration},
+};
+
+/// How far back the current rate looks
+const RATE_WINDOW_MS: f64 = 5000.0;
+/// How often rates are recalculated while nothing is being transferred, so a stalled transfer
+/// shows as stalled
+const RATE_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Byte counters for a transfer. Only bytes confirmed by the receiver are counted
+#[derive(Clone, Copy)]
+pub(crate) struct Progress {
+    pub total: RwSignal<u64>,
+    pub done: RwSignal<u64>,
+    /// Bytes per second over the last few seconds
+    pub rate: RwSignal<f64>,
+    /// Bytes per second since the transfer started, not counting bytes saved by an earlier
+    /// attempt
+    pub average_rate: RwSignal<f64>,
+    /// Size of the transferred chunks before and after compression
+    pub uncompressed: RwSignal<u64>,
+    pub compressed: RwSignal<u64>,
+    samples: StoredValue<Samples>,
+}
+
+#[derive(Default)]
+struct Samples {
+    started_at: Option<f64>,
+    transferred: u64,
+    /// Time and size of each recent advance, oldest first
+    recent: VecDeque<(f64, u64)>,
+}
+
+impl Progress {
+    pub fn new(total: u64) -> Progress {
+        Progress {
+            total: create_rw_signal(total),
+            done: create_rw_signal(0),
+            rate: create_rw_signal(0.0),
+            average_rate: create_rw_signal(0.0),
+            uncompressed: create_rw_signal(0),
+            compressed: create_rw_signal(0),
+            samples: store_value(Samples::default()),
+        }
+    }
+
+    /// Counts bytes that were already there, such as those of a resumed download, without
+    /// affecting the rates
+    pub fn skip(&self, bytes: u64) {
+        self.done.update(|done| *done += bytes);
+    }
+
+    pub fn advance(&self, bytes: u64) {
+        let now = Date::now();
+        self.samples.update_value(|samples| {
+            samples.started_at.get_or_insert(now);
+            samples.transferred += bytes;
+            samples.recent.push_back((now, bytes));
+        });
+        self.done.update(|done| *done += bytes);
+
+        self.calculate_rates(now);
+    }
+
+    /// Counts a chunk towards the compression ratio
+    pub fn record_compression(&self, uncompressed: u64, compressed: u64) {
+        self.uncompressed.update(|total| *total += uncompressed);
+        self.compressed.update(|total| *total += compressed);
+    }
+
+    /// Recalculates the rates, unless the transfer has finished
+    pub fn update_rates(&self) {
+        if self.done.get_untracked() < self.total.get_untracked() {
+            self.calculate_rates(Date::now());
+        }
+    }
+
+    fn calculate_rates(&self, now: f64) {
+        self.samples.update_value(|samples| {
+            let Some(started_at) = samples.started_at else {
+                return;
+            };
+
+            while samples
+                .recent
+                .front()
+                .is_some_and(|(time, _)| *time < now - RATE_WINDOW_MS)
+            {
+                samples.recent.pop_front();
+            }
+
+            let elapsed = (now - started_at).max(1.0);
+            let window = elapsed.min(RATE_WINDOW_MS);
+            let recent_bytes = samples.recent.iter().map(|(_, bytes)| bytes).sum::<u64>();
+
+            self.rate.set(recent_bytes as f64 * 1000.0 / window);
+            self.average_rate
+                .set(samples.transferred as f64 * 1000.0 / elapsed);
+        });
+    }
+
+    /// Estimated seconds left, from the current rate
+    pub fn eta(&self) -> Option<f64> {
+        let remaining = self.total.get().saturating_sub(self.done.get());
+        let rate = self.rate.get();
+
+        (remaining > 0 && rate > 0.0).then(|| remaining as f64 / rate)
+    }
+}
+
+pub(crate) fn progress_view(progress: Progress) -> impl IntoView {
+    let interval =
+        set_interval_with_handle(move || progress.update_rates(), RATE_UPDATE_INTERVAL).ok();
+    on_cleanup(move || {
+        if let Some(interval) = interval {
+            interval.clear();
+        }
+    });
+
+    let summary = move || {
+        let done = progress.done.get();
+        let total = progress.total.get();
+        let started = progress.average_rate.get() > 0.0;
+        if done >= total && !started {
+            return format_bytes(total);
+        }
+        if done >= total {
+            return format!(
+                "{} at {}/s average",
+                format_bytes(total),
+                format_bytes(progress.average_rate.get() as u64)
+            );
+        }
+
+        let eta = match progress.eta() {
+            Some(eta) => format!("{} left", format_duration(eta)),
+            None if started => "stalled".to_string(),
+            None => "waiting".to_string(),
+        };
+        format!(
+            "{} of {}, {}/s ({}/s average), {eta}",
+            format_bytes(done),
+            format_bytes(total),
+            format_bytes(progress.rate.get() as u64),
+            format_bytes(progress.average_rate.get() as u64),
+        )
+    };
+
+    let compression = move || {
+        let uncompressed = progress.uncompressed.get();
+        let compressed = progress.compressed.get();
+
+        (compressed < uncompressed)
+            .then(|| format!("Compressed {}", format_ratio(uncompressed, compressed)))
+    };
+
+    view! {
+        <div>
+            <progress max=move || progress.total.get() value=move || progress.done.get()></progress>
+            <div>{summary}</div>
+            <div>{compression}</div>
+        </div>
+    }
+}
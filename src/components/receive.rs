@@ -1,34 +1,52 @@
 use std::rc::Rc;
 
-use js_sys::{Array, ArrayBuffer};
-use leptos::*;
+use leptos::{html::Input, *};
 use leptos_meta::Title;
 use leptos_router::{use_params, NavigateOptions, Params};
 use log::{error, info};
-use tokio::select;
+use tokio::{select, sync::mpsc};
 use tokio_util::sync::CancellationToken;
-use wasm_bindgen::JsCast;
-use web_sys::{Blob, HtmlAnchorElement, Url};
 
 use crate::{
-    components::{app::CONNECT_TIMEOUT, settings::Settings},
+    components::{
+        app::CONNECT_TIMEOUT,
+        session::{run_session, session_view, Requests, Session, SessionError},
+        settings::Settings,
+        swarm::{serve_seeds, SeedConnects, Seeding},
+    },
+    crypto::{parse_share_code, Cipher, ShareKey},
     peerjs::{
         client::{Client, ClientError},
         dataconnection::DataConnectionError,
         peerid::PeerID,
     },
-    utils::timeout,
+    protocol::{
+        exchange_password, handshake, negotiate_connections, receive_message, HandshakeError, Link,
+        Message, PasswordError, CAPABILITY_SWARM, CHUNKS_LABEL, CONTROL_LABEL,
+        KEYLESS_CONTROL_LABEL,
+    },
+    utils::{format_bytes, timeout},
+    verification::verification_phrase,
 };
 
 #[derive(Params, PartialEq, Clone, Debug)]
 pub struct ReceiveFileParams {
-    peer_id: String,
+    code: String,
 }
 
 struct Status {
     message: String,
+    failed: bool,
 }
 
+/// Derived from the connection's DTLS fingerprints, for the user to compare with the sender
+#[derive(Clone)]
+struct Verification(String);
+
+/// Set while waiting for the user to type the share's password
+#[derive(Clone)]
+struct PasswordPrompt(mpsc::Sender<String>);
+
 #[derive(Debug, thiserror::Error)]
 enum ReceiveFileError {
     #[error("Error while connecting to PeerJS: {0}")]
@@ -39,10 +57,16 @@ enum ReceiveFileError {
     OpenDataConnectionError(DataConnectionError),
     #[error("Data connection open timed out")]
     OpenDataConnectionTimedOut,
-    #[error("Error while receiving file info: {0}")]
-    ReceiveFilenameDataConnectionError(DataConnectionError),
-    #[error("Error while receiving file info: {0}")]
-    ReceiveDataDataConnectionError(DataConnectionError),
+    #[error("{0}")]
+    HandshakeError(HandshakeError),
+    #[error("{0}")]
+    PasswordError(PasswordError),
+    #[error("Password prompt closed unexpectedly")]
+    PasswordPromptClosed,
+    #[error("The share has no password, so the link needs its key")]
+    KeyRequired,
+    #[error("{0}")]
+    SessionError(SessionError),
 }
 
 #[component]
@@ -52,56 +76,146 @@ pub(crate) fn ReceiveFile() -> impl IntoView {
 
     let status = Status {
         message: "Initializing".to_string(),
+        failed: false,
     };
     let (status, set_status) = create_signal(Rc::new(status));
     provide_context(status);
     provide_context(set_status);
 
-    let Ok(peer_id) = params.get_untracked().map(|v| v.peer_id) else {
-        error!("No peer id in params");
+    let verification = create_rw_signal::<Option<Verification>>(None);
+    provide_context(verification);
+
+    let password_prompt = create_rw_signal::<Option<PasswordPrompt>>(None);
+    provide_context(password_prompt);
+
+    let Ok(code) = params.get_untracked().map(|v| v.code) else {
+        error!("No share code in params");
         navigate("/", NavigateOptions::default());
         return view! { <div></div> };
     };
-    let Some(peer_id) = PeerID::new(peer_id.clone()) else {
-        error!("Invalid peer id: {peer_id}");
+    let Some((peer_id, key)) = parse_share_code(&code) else {
+        error!("Invalid share code: {code}");
         navigate("/", NavigateOptions::default());
         return view! { <div></div> };
     };
-
     let title_text = format!("Receiving from {}", peer_id.base());
 
+    let (session, requests) = Session::new("sender", peer_id.full().to_string());
+
+    // Provided so an accepted share with a swarm can be joined
+    let settings = use_context::<ReadSignal<Rc<Settings>>>()
+        .unwrap()
+        .get_untracked();
+    let seeding = settings.swarm.get_untracked().then(Seeding::new);
+    let passed_on = seeding.as_ref().map(|(seeding, _)| seeding.passed_on);
+    if let Some((seeding, _)) = &seeding {
+        provide_context(seeding.clone());
+    }
+
     let cancel_token = CancellationToken::new();
-    spawn_local_with_current_owner(receive_file(peer_id, cancel_token.clone())).unwrap();
+    spawn_local_with_current_owner(receive_file(
+        peer_id,
+        key,
+        session,
+        requests,
+        seeding,
+        cancel_token.clone(),
+    ))
+    .unwrap();
     on_cleanup(move || cancel_token.cancel());
 
+    let verification_view = move || {
+        verification.get().map(|Verification(phrase)| {
+            view! {
+                <div>{format!("Verification phrase: {phrase}")}</div>
+                <div>"Check it matches the phrase the sender sees"</div>
+            }
+        })
+    };
+
+    let password_input_ref = create_node_ref::<Input>();
+    let password_view = move || {
+        let PasswordPrompt(password_tx) = password_prompt.get()?;
+
+        let on_unlock_click = move |_| {
+            let Some(input) = password_input_ref() else {
+                return;
+            };
+            let _ = password_tx.try_send(input.value());
+            password_prompt.set(None);
+        };
+
+        Some(view! {
+            <div>"The sender set a password"</div>
+            <input type="password" node_ref=password_input_ref/>
+            <div on:click=on_unlock_click>"Unlock"</div>
+        })
+    };
+
+    let passed_on_view = move || {
+        let passed_on = passed_on?.get();
+        (passed_on > 0).then(|| {
+            view! { <div>{format!("Passed on {} to other receivers", format_bytes(passed_on))}</div> }
+        })
+    };
+
+    let retry_view = move || {
+        if !status.get().failed {
+            return None;
+        }
+
+        Some(view! {
+            <div on:click=move |_| window().location().reload().unwrap()>"Retry"</div>
+        })
+    };
+
     view! {
         <div>
             <Title text=title_text/>
             <div>{move || status.get().message.clone()}</div>
+            {verification_view}
+            {password_view}
+            {passed_on_view}
+            {retry_view}
+            {session_view(session)}
         </div>
     }
 }
 
-async fn receive_file(peer_id: PeerID, cancel_token: CancellationToken) {
+async fn receive_file(
+    peer_id: PeerID,
+    key: Option<ShareKey>,
+    session: Session,
+    requests: Requests,
+    seeding: Option<(Seeding, SeedConnects)>,
+    cancel_token: CancellationToken,
+) {
     let result = select! {
-        v = receive_file_inner(peer_id) => v,
+        v = receive_file_inner(peer_id, key, session, requests, seeding) => v,
         _ = cancel_token.cancelled() => {
             return;
         },
     };
 
     if let Err(error) = result {
-        update_status(error.to_string());
+        update_status_failed(error.to_string());
         cancel_token.cancel();
     }
 }
 
-async fn receive_file_inner(peer_id: PeerID) -> Result<(), ReceiveFileError> {
+async fn receive_file_inner(
+    peer_id: PeerID,
+    key: Option<ShareKey>,
+    session: Session,
+    requests: Requests,
+    seeding: Option<(Seeding, SeedConnects)>,
+) -> Result<(), ReceiveFileError> {
     update_status("Connecting to peerjs");
 
-    let servers = use_context::<ReadSignal<Rc<Settings>>>()
+    let settings = use_context::<ReadSignal<Rc<Settings>>>()
         .unwrap()
-        .get_untracked()
+        .get_untracked();
+    let servers = settings
         .servers
         .get_untracked()
         .iter()
@@ -116,70 +230,112 @@ async fn receive_file_inner(peer_id: PeerID) -> Result<(), ReceiveFileError> {
 
     update_status("Opening data connection to peer");
 
-    let mut connection = client.connect(peer_id);
+    // Without the key, only the password exchange keeps the connection private
+    let (label, cipher) = match &key {
+        Some(key) => (CONTROL_LABEL, key.cipher()),
+        None => (KEYLESS_CONTROL_LABEL, Cipher::keyless()),
+    };
+    let mut connection = client.connect(&peer_id, label);
 
     timeout(CONNECT_TIMEOUT, connection.wait_for_open())
         .await
         .map_err(|_| ReceiveFileError::OpenDataConnectionTimedOut)?
         .map_err(ReceiveFileError::OpenDataConnectionError)?;
 
-    update_status("Waiting for file info");
+    if let Some((local, remote)) = connection.fingerprints() {
+        let phrase = verification_phrase(&local, &remote);
+        use_context::<RwSignal<Option<Verification>>>()
+            .unwrap()
+            .set(Some(Verification(phrase)));
+    }
 
-    let filename = connection
-        .receive::<String>()
+    let mut link = Link::new(connection, "sender");
+    let capabilities = match seeding {
+        Some(_) => &[CAPABILITY_SWARM][..],
+        None => &[],
+    };
+    let peer = handshake(&mut link, &cipher, capabilities)
         .await
-        .map_err(ReceiveFileError::ReceiveFilenameDataConnectionError)?;
-
-    update_status(format!("Receiving {filename}"));
+        .map_err(ReceiveFileError::HandshakeError)?;
+    let connections =
+        negotiate_connections(&mut link, &cipher, settings.connections.get_untracked())
+            .await
+            .map_err(ReceiveFileError::HandshakeError)?;
+    if connections > 1 {
+        update_status(format!("Opening {connections} connections"));
+        let extras = (1..connections)
+            .map(|_| client.connect(&peer_id, CHUNKS_LABEL))
+            .collect::<Vec<_>>();
+        for mut extra in extras {
+            timeout(CONNECT_TIMEOUT, extra.wait_for_open())
+                .await
+                .map_err(|_| ReceiveFileError::OpenDataConnectionTimedOut)?
+                .map_err(ReceiveFileError::OpenDataConnectionError)?;
+            link.add_connection(extra);
+        }
+    }
 
-    let data = connection
-        .receive::<ArrayBuffer>()
+    let message = receive_message(&mut link, &cipher)
         .await
-        .map_err(ReceiveFileError::ReceiveDataDataConnectionError)?;
+        .map_err(|error| ReceiveFileError::PasswordError(PasswordError::ReceiveError(error)))?;
+    let cipher = match message {
+        Message::PasswordRequired(false) if key.is_none() => {
+            return Err(ReceiveFileError::KeyRequired);
+        }
+        Message::PasswordRequired(false) => cipher,
+        Message::PasswordRequired(true) => {
+            update_status("Waiting for the password");
+            let password = ask_password().await?;
+            update_status("Checking password");
+            exchange_password(&mut link, &cipher, &password)
+                .await
+                .map_err(ReceiveFileError::PasswordError)?
+        }
+        _ => {
+            return Err(ReceiveFileError::PasswordError(
+                PasswordError::UnexpectedMessage,
+            ))
+        }
+    };
 
-    info!("Data size: {}", data.byte_length());
+    update_status("Connected");
 
-    save_file(&filename, data);
+    // Other receivers of the share can fetch chunks from this page for as long as it's connected
+    let result = select! {
+        result = run_session(&mut link, &cipher, session, requests, &peer, true) => result,
+        never = serve_seeds(&mut client, seeding) => match never {},
+    };
+    result.map_err(ReceiveFileError::SessionError)?;
 
-    update_status(format!("Saved {filename}"));
+    update_status("Connection closed");
 
     Ok(())
 }
 
-fn update_status<T: ToString>(message: T) {
-    let message = message.to_string();
-
-    info!("Status: {}", &message);
+/// Waits for the user to type the share's password
+async fn ask_password() -> Result<String, ReceiveFileError> {
+    let (password_tx, mut password_rx) = mpsc::channel(1);
+    use_context::<RwSignal<Option<PasswordPrompt>>>()
+        .unwrap()
+        .set(Some(PasswordPrompt(password_tx)));
 
-    let set_status = use_context::<WriteSignal<Rc<Status>>>().unwrap();
-    set_status(Rc::new(Status { message }));
+    password_rx
+        .recv()
+        .await
+        .ok_or(ReceiveFileError::PasswordPromptClosed)
 }
 
-fn save_file(filename: &str, data: ArrayBuffer) {
-    let buffer_array = Array::new();
-    buffer_array.push(&data);
-    let blob = Blob::new_with_buffer_source_sequence(&buffer_array).unwrap();
-    let url = Url::create_object_url_with_blob(&blob).unwrap();
-
-    let anchor_element = document()
-        .create_element("a")
-        .unwrap()
-        .dyn_into::<HtmlAnchorElement>()
-        .unwrap();
-    anchor_element.set_href(&url);
-    anchor_element.set_attribute("download", filename).unwrap();
+fn update_status<T: ToString>(message: T) {
+    set_status(message.to_string(), false);
+}
 
-    document()
-        .body()
-        .unwrap()
-        .append_child(&anchor_element)
-        .unwrap();
+fn update_status_failed<T: ToString>(message: T) {
+    set_status(message.to_string(), true);
+}
 
-    anchor_element.click();
+fn set_status(message: String, failed: bool) {
+    info!("Status: {}", &message);
 
-    document()
-        .body()
-        .unwrap()
-        .remove_child(&anchor_element)
-        .unwrap();
+    let set_status = use_context::<WriteSignal<Rc<Status>>>().unwrap();
+    set_status(Rc::new(Status { message, failed }));
 }
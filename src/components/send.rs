@@ -1,37 +1,138 @@
-use std::rc::Rc;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    rc::Rc,
+    time::Duration,
+};
 
+use js_sys::Date;
 use leptos::*;
 use leptos_meta::Title;
 use leptos_router::NavigateOptions;
-use log::{error, info};
-use tokio::select;
+use log::{info, warn};
+use tokio::{join, select, sync::mpsc};
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
-use web_sys::File;
+use web_sys::Event;
 
 use crate::{
     components::{
-        app::{FileToSend, CONNECT_TIMEOUT},
+        app::{FileToSend, TextToSend, CONNECT_TIMEOUT},
+        outgoing::{header_view, share_files, HashFileError, Payload},
+        session::{run_session, session_view, Requests, Session, SessionError},
         settings::Settings,
+        swarm::{Swarm, SwarmMember},
     },
+    crypto::{share_code, Cipher, ShareKey},
+    files::SelectedFile,
     peerjs::{
         client::{Client, ClientError},
         dataconnection::{DataConnection, DataConnectionError},
         peerid::PeerID,
     },
-    utils::timeout,
+    protocol::{
+        exchange_password, handshake, negotiate_connections, send_message, FileHeader,
+        HandshakeError, Link, Message, PasswordError, ShareEnd, CAPABILITY_SWARM, CHUNKS_LABEL,
+        KEYLESS_CONTROL_LABEL,
+    },
+    queue::UploadQueue,
+    utils::{format_bytes, sleep, timeout},
+    verification::verification_phrase,
 };
 
+/// How long connection attempts are remembered, in milliseconds
+const ATTEMPT_WINDOW: f64 = 60_000.0;
+/// Most connection attempts from one peer within `ATTEMPT_WINDOW`
+const MAX_ATTEMPTS: usize = 5;
+/// Most connection attempts from every peer together within `ATTEMPT_WINDOW`. Peer IDs are picked
+/// by the peer, so this is what actually limits a peer that keeps reloading
+const MAX_SHARE_ATTEMPTS: usize = 30;
+/// How long every connection is turned away once `MAX_SHARE_ATTEMPTS` is reached, in
+/// milliseconds. It doubles each time the limit is hit again, up to `MAX_BACKOFF`
+const MIN_BACKOFF: f64 = 10_000.0;
+const MAX_BACKOFF: f64 = 600_000.0;
+/// How long wrong passwords are remembered, in milliseconds
+const WRONG_PASSWORD_WINDOW: f64 = 600_000.0;
+/// Most wrong passwords from every peer together within `WRONG_PASSWORD_WINDOW`. Each password
+/// exchange only checks one guess, so this bounds how fast the password can be guessed
+const MAX_WRONG_PASSWORDS: usize = 10;
+
 #[derive(Clone)]
 struct PeerStatus {
     message: RwSignal<String>,
 }
 
+/// Optional message sent to receivers with the file list
+#[derive(Clone, Copy)]
+struct ShareNote(RwSignal<String>);
+
+/// Password receivers have to type before anything about the files is sent. Empty for none
+#[derive(Clone, Copy)]
+struct SharePassword(RwSignal<String>);
+
+/// When each recent password exchange failed. Shared by every connection, as a peer can pick a
+/// new peer ID for each guess
+#[derive(Clone, Copy)]
+struct WrongPasswords(StoredValue<VecDeque<f64>>);
+
+/// Limits set on the send page. Once one runs out, the share closes and every peer is told why
+#[derive(Clone, Copy)]
+struct SharePolicy {
+    /// Completed downloads after which the share closes
+    max_downloads: RwSignal<Option<u32>>,
+    /// When the share closes, in milliseconds since the epoch
+    expires_at: RwSignal<Option<f64>>,
+    /// Whether the share closes after the first completed download
+    burn: RwSignal<bool>,
+    /// Peers that have been sent everything
+    downloads: RwSignal<u32>,
+    /// Why the share closed, once it has
+    ended: RwSignal<Option<ShareEnd>>,
+    cancel_token: StoredValue<CancellationToken>,
+}
+
+/// Peers turned away for the rest of the share, by peer ID or by their connection's DTLS
+/// fingerprint. Neither outlasts a reload, which brings a new peer ID and usually a new
+/// certificate, so it's the share-wide attempt limit that keeps a blocked peer from retrying often
+#[derive(Clone, Copy)]
+struct Blocked {
+    peer_ids: RwSignal<HashSet<String>>,
+    fingerprints: RwSignal<HashSet<String>>,
+}
+
+/// Recent connection attempts, to slow down peers that keep connecting
+struct Attempts {
+    /// When each peer last tried to connect, by peer ID
+    by_peer: HashMap<String, VecDeque<f64>>,
+    /// When any peer last tried to connect
+    all: VecDeque<f64>,
+    /// Every connection is turned away until then, in milliseconds since the epoch
+    backoff_until: f64,
+    /// How long the next back-off lasts
+    backoff: f64,
+}
+
 #[derive(Clone)]
 struct Connection {
     id: Uuid,
     peer_id: String,
     status: RwSignal<String>,
+    session: Session,
+    /// Set when the share's receivers pass chunks on to each other
+    swarm: Option<Swarm>,
+    /// Set when the peer's link leaves the key out, so only the password protects the connection
+    keyless: bool,
+    /// Cancelled to disconnect the peer, or when the share closes
+    disconnect: CancellationToken,
+    /// The peer's DTLS fingerprint, once the connection is open
+    fingerprint: RwSignal<Option<String>>,
+    /// Derived from the connection's DTLS fingerprints, for the user to compare with the receiver
+    verification: RwSignal<Option<String>>,
+    /// Set while waiting for the user to approve the connection
+    approval: RwSignal<Option<mpsc::Sender<bool>>>,
+    /// Set while waiting for the user to confirm the verification phrase matches
+    confirmation: RwSignal<Option<mpsc::Sender<bool>>>,
+    /// Set once the peer has been sent everything, counting as one of the share's downloads
+    downloaded: RwSignal<bool>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -40,18 +141,42 @@ enum ReceiveConnectionsError {
     OpenError(ClientError),
     #[error("PeerJS open timed out")]
     OpenTimedOut,
+    #[error("{0}")]
+    HashFileError(HashFileError),
     #[error("Error while receiving connection: {0}")]
     ReceiveConnectionError(ClientError),
 }
 
 #[derive(Debug, thiserror::Error)]
-enum SendFileError {
+enum ConnectionError {
     #[error("Error while opening data connection: {0}")]
     OpenDataConnectionError(DataConnectionError),
     #[error("Data connection open timed out")]
     OpenDataConnectionTimedOut,
-    #[error("Error while waiting for close: {0}")]
-    CloseError(DataConnectionError),
+    #[error("{0}")]
+    HandshakeError(HandshakeError),
+    #[error("{0}")]
+    PasswordError(PasswordError),
+    #[error("Connection denied")]
+    Denied,
+    #[error("Disconnected")]
+    Disconnected,
+    #[error("Blocked")]
+    Blocked,
+    #[error("Peer's link has no key, but the share has no password")]
+    KeyRequired,
+    #[error("Too many wrong passwords. Turned away until some expire")]
+    TooManyWrongPasswords,
+    #[error("Approval prompt closed unexpectedly")]
+    ApprovalClosed,
+    #[error("Couldn't read the connection's fingerprints to derive a verification phrase")]
+    VerificationUnavailable,
+    #[error("Verification phrase rejected")]
+    VerificationRejected,
+    #[error("Verification prompt closed unexpectedly")]
+    ConfirmationClosed,
+    #[error("{0}")]
+    SessionError(SessionError),
 }
 
 #[component]
@@ -60,51 +185,136 @@ pub(crate) fn SendFile() -> impl IntoView {
 
     let file_to_send = use_context::<ReadSignal<FileToSend>>().unwrap();
     let set_file_to_send = use_context::<WriteSignal<FileToSend>>().unwrap();
+    let text_to_send = use_context::<ReadSignal<TextToSend>>().unwrap();
+    let set_text_to_send = use_context::<WriteSignal<TextToSend>>().unwrap();
 
-    let Some(file) = file_to_send.get_untracked().0 else {
-        info!("FileToSend not set. Redirecting to menu");
+    let files = file_to_send.get_untracked().0;
+    let text = text_to_send.get_untracked().0;
+    if files.is_empty() && text.is_none() {
+        info!("FileToSend and TextToSend not set. Redirecting to menu");
         navigate("/", NavigateOptions::default());
         return view! { <div></div> };
-    };
-    set_file_to_send.set_untracked(FileToSend(None));
+    }
+    set_file_to_send.set_untracked(FileToSend(Vec::new()));
+    set_text_to_send.set_untracked(TextToSend(None));
 
     let status = PeerStatus {
         message: create_rw_signal("Initializing".to_string()),
     };
     provide_context(status.clone());
 
+    let headers = create_rw_signal(Vec::<FileHeader>::new());
+    provide_context(headers);
+
+    let note = ShareNote(create_rw_signal(String::new()));
+    provide_context(note);
+
+    let password = SharePassword(create_rw_signal(String::new()));
+    provide_context(password);
+    provide_context(WrongPasswords(store_value(VecDeque::new())));
+
+    let cancel_token = CancellationToken::new();
+    let policy = SharePolicy {
+        max_downloads: create_rw_signal(None),
+        expires_at: create_rw_signal(None),
+        burn: create_rw_signal(false),
+        downloads: create_rw_signal(0),
+        ended: create_rw_signal(None),
+        cancel_token: store_value(cancel_token.clone()),
+    };
+    provide_context(policy);
+
+    provide_context(Blocked {
+        peer_ids: create_rw_signal(HashSet::new()),
+        fingerprints: create_rw_signal(HashSet::new()),
+    });
+
     let connections = Vec::<Connection>::new();
     let (connections, set_connections) = create_signal(connections);
     provide_context(connections);
     provide_context(set_connections);
 
+    let settings = use_context::<ReadSignal<Rc<Settings>>>()
+        .unwrap()
+        .get_untracked();
+    // Text is sent whole with the offer, so there's nothing to pass on
+    let swarm = (settings.swarm.get_untracked() && text.is_none()).then(Swarm::new);
+
     let client_id = PeerID::new_random_short_id();
+    let key = ShareKey::generate();
+    provide_context(key.cipher());
 
-    let title_text = format!("Sending {}", file.name());
-    let client_id_string = client_id.base().to_string();
+    let title_text = match (files.as_slice(), &text) {
+        (_, Some(_)) => "Sending text".to_string(),
+        ([file], None) => format!("Sending {}", file.path),
+        (files, None) => format!("Sending {} files", files.len()),
+    };
+    let text_view = text.clone().map(|text| view! { <pre>{text}</pre> });
+    let code = share_code(&client_id, Some(&key));
     let base_uri = document().base_uri().unwrap().unwrap();
-    let sharing_link = format!("{base_uri}#{}", client_id.base());
+    let sharing_link = format!("{base_uri}#{code}");
+    let keyless_link = format!("{base_uri}#{}", share_code(&client_id, None));
+    let keyless_link_view = move || {
+        (!password.0.get().is_empty()).then(|| {
+            view! {
+                <div>
+                    <div>"Or share this link, which leaves the key out and relies on the password alone"</div>
+                    <a>{keyless_link.clone()}</a>
+                </div>
+            }
+        })
+    };
 
-    let cancel_token = CancellationToken::new();
-    spawn_local_with_current_owner(receive_connections(client_id, file, cancel_token.clone()))
-        .unwrap();
+    spawn_local_with_current_owner(receive_connections(
+        client_id,
+        files,
+        text,
+        swarm,
+        cancel_token.clone(),
+    ))
+    .unwrap();
     on_cleanup(move || cancel_token.cancel());
 
     view! {
         <div>
             <Title text=title_text/>
             <div>
-                <div>"Client id:"</div>
-                <div>{client_id_string}</div>
+                <div>"Code:"</div>
+                <div>{code}</div>
             </div>
             <div>
                 <div>"Share this link"</div>
                 <a>{sharing_link}</a>
             </div>
+            <div>
+                <div>"Note for receivers"</div>
+                <textarea
+                    prop:value=move || note.0.get()
+                    on:input=move |event| note.0.set(event_target_value(&event))
+                ></textarea>
+            </div>
+            <div>
+                <div>"Password"</div>
+                <input
+                    type="password"
+                    placeholder="None"
+                    prop:value=move || password.0.get()
+                    on:input=move |event| password.0.set(event_target_value(&event))
+                />
+            </div>
+            {keyless_link_view}
+            {policy_view(policy)}
+            {swarm.map(swarm_view)}
             <div>
                 <div>"Status"</div>
                 <div>{move || status.message.get()}</div>
             </div>
+            {text_view}
+            <For
+                each=move || headers.get()
+                key=|header| header.path.clone()
+                children=header_view
+            />
             <div>"Connections"</div>
             <For
                 each=move || connections.get()
@@ -115,43 +325,215 @@ pub(crate) fn SendFile() -> impl IntoView {
     }
 }
 
+fn policy_view(policy: SharePolicy) -> impl IntoView {
+    let on_max_downloads_change = move |event: Event| {
+        let max_downloads = event_target_value(&event).parse::<u32>().ok();
+        policy
+            .max_downloads
+            .set(max_downloads.filter(|max| *max > 0));
+    };
+    let on_expiry_change = move |event: Event| {
+        let minutes = event_target_value(&event).parse::<f64>().ok();
+        policy.expires_at.set(
+            minutes
+                .filter(|minutes| *minutes > 0.0)
+                .map(|minutes| Date::now() + minutes * 60_000.0),
+        );
+    };
+
+    let downloads_view = move || match policy.max_downloads.get() {
+        Some(max_downloads) => format!("Downloads: {} of {max_downloads}", policy.downloads.get()),
+        None => format!("Downloads: {}", policy.downloads.get()),
+    };
+    let expiry_view = move || {
+        policy.expires_at.get().map(|expires_at| {
+            let time = String::from(Date::new(&expires_at.into()).to_locale_time_string("default"));
+            format!("Expires at {time}")
+        })
+    };
+
+    view! {
+        <div>
+            <label>
+                <input type="number" min="1" placeholder="Unlimited" on:change=on_max_downloads_change/>
+                "Most downloads"
+            </label>
+            <label>
+                <input type="number" min="1" placeholder="Never" on:change=on_expiry_change/>
+                "Expire after this many minutes"
+            </label>
+            <label>
+                <input
+                    type="checkbox"
+                    prop:checked=move || policy.burn.get()
+                    on:change=move |event| policy.burn.set(event_target_checked(&event))
+                />
+                "Burn after the first download"
+            </label>
+            <div>{downloads_view}</div>
+            <div>{expiry_view}</div>
+        </div>
+    }
+}
+
+/// How much of the share the receivers passed on to each other, next to what the sender sent
+fn swarm_view(swarm: Swarm) -> impl IntoView {
+    view! {
+        <div>
+            {move || format!(
+                "Passed on by receivers: {}, sent from here: {}",
+                format_bytes(swarm.total_carried()),
+                format_bytes(swarm.sent.get()),
+            )}
+        </div>
+    }
+}
+
 fn connection_view(connection: Connection) -> impl IntoView {
+    let disconnect = connection.disconnect.clone();
+    let blocked_connection = connection.clone();
+
+    let peer_id = connection.peer_id.clone();
+    let carried_view = connection.swarm.map(|swarm| {
+        move || {
+            format!(
+                "Passed on {} to other receivers",
+                format_bytes(swarm.carried_by(&peer_id))
+            )
+        }
+    });
+
+    let verification = connection.verification;
+    let verification_view = move || {
+        verification
+            .get()
+            .map(|phrase| view! { <div>{format!("Verification phrase: {phrase}")}</div> })
+    };
+
     view! {
         <div>
             <div>{&connection.peer_id}</div>
             <div>{move || connection.status.get()}</div>
+            <div>{carried_view}</div>
+            <div on:click=move |_| disconnect.cancel()>"Disconnect"</div>
+            <div on:click=move |_| block(&blocked_connection)>"Block"</div>
+            {verification_view}
+            {choice_view(connection.approval, "Approve", "Deny")}
+            {choice_view(connection.confirmation, "Phrases match", "Phrases don't match")}
+            {session_view(connection.session)}
         </div>
     }
 }
 
-async fn receive_connections(client_id: PeerID, file: File, cancel_token: CancellationToken) {
+/// Disconnects the peer and turns it away if it comes back with the same peer ID or certificate
+fn block(connection: &Connection) {
+    let blocked = use_context::<Blocked>().unwrap();
+    blocked.peer_ids.update(|peer_ids| {
+        peer_ids.insert(connection.peer_id.clone());
+    });
+    if let Some(fingerprint) = connection.fingerprint.get_untracked() {
+        blocked.fingerprints.update(|fingerprints| {
+            fingerprints.insert(fingerprint);
+        });
+    }
+    connection.disconnect.cancel();
+}
+
+/// Yes and no buttons, shown while `prompt` holds a sender for the answer
+fn choice_view(
+    prompt: RwSignal<Option<mpsc::Sender<bool>>>,
+    yes_text: &'static str,
+    no_text: &'static str,
+) -> impl IntoView {
+    move || {
+        let choice_tx = prompt.get()?;
+
+        let no_tx = choice_tx.clone();
+        let on_yes_click = move |_| {
+            let _ = choice_tx.try_send(true);
+            prompt.set(None);
+        };
+        let on_no_click = move |_| {
+            let _ = no_tx.try_send(false);
+            prompt.set(None);
+        };
+
+        Some(view! {
+            <div on:click=on_yes_click>{yes_text}</div>
+            <div on:click=on_no_click>{no_text}</div>
+        })
+    }
+}
+
+async fn receive_connections(
+    client_id: PeerID,
+    files: Vec<SelectedFile>,
+    text: Option<String>,
+    swarm: Option<Swarm>,
+    cancel_token: CancellationToken,
+) {
+    let policy = use_context::<SharePolicy>().unwrap();
+    // Kept out here, so it outlives the connections
+    let mut client = None;
+    // Held by each connection's task
+    let (alive_tx, mut alive_rx) = mpsc::channel::<()>(1);
     let result = select! {
-        v = receive_connections_inner(client_id, file, cancel_token.clone()) => v,
-        _ = cancel_token.cancelled() => {
-            return;
-        },
+        v = receive_connections_inner(
+            &mut client, client_id, files, text, swarm, alive_tx, cancel_token.clone(),
+        ) => v,
+        _ = enforce_policy(policy) => Ok(()),
+        _ = cancel_token.cancelled() => Ok(()),
     };
 
     if let Err(error) = result {
         update_peer_status(error.to_string());
-        cancel_token.cancel();
     }
+
+    // Dropping the client closes every connection, so each peer is told why it's being
+    // disconnected first
+    cancel_token.cancel();
+    let _ = alive_rx.recv().await;
+    drop(client);
 }
 
 async fn receive_connections_inner(
+    client: &mut Option<Client>,
     client_id: PeerID,
-    file: File,
+    files: Vec<SelectedFile>,
+    text: Option<String>,
+    swarm: Option<Swarm>,
+    alive_tx: mpsc::Sender<()>,
     cancel_token: CancellationToken,
 ) -> Result<(), ReceiveConnectionsError> {
-    let servers = use_context::<ReadSignal<Rc<Settings>>>()
+    let payload = match text {
+        Some(text) => Payload::Text(Rc::new(text)),
+        None => {
+            let file_count = files.len();
+            let shared_files = share_files(files, |path, i| {
+                update_peer_status(format!("Hashing {path} ({}/{file_count})", i + 1));
+            })
+            .await
+            .map_err(ReceiveConnectionsError::HashFileError)?;
+            use_context::<RwSignal<Vec<FileHeader>>>().unwrap().set(
+                shared_files
+                    .iter()
+                    .map(|file| file.header.clone())
+                    .collect(),
+            );
+            Payload::Files(Rc::new(shared_files))
+        }
+    };
+
+    let settings = use_context::<ReadSignal<Rc<Settings>>>()
         .unwrap()
-        .get_untracked()
+        .get_untracked();
+    let servers = settings
         .servers
         .get_untracked()
         .iter()
         .map(|server| server.to_js())
         .collect();
-    let mut client = Client::new(client_id, servers);
+    let client = client.insert(Client::new(client_id, servers));
 
     timeout(CONNECT_TIMEOUT, client.wait_for_open())
         .await
@@ -160,80 +542,470 @@ async fn receive_connections_inner(
 
     update_peer_status("Waiting for connections");
 
+    // Shared by every connection, so only a few peers are sent to at once
+    let queue = UploadQueue::new(settings.max_uploads.get_untracked());
+
+    let blocked = use_context::<Blocked>().unwrap();
+    let cipher = use_context::<Cipher>().unwrap();
+    // Where to pass each peer's extra connections, by peer ID
+    let mut extra_txs = HashMap::<String, mpsc::UnboundedSender<DataConnection>>::new();
+    let mut attempts = Attempts::new();
     loop {
         let connection = client
             .receive_connection()
             .await
             .map_err(ReceiveConnectionsError::ReceiveConnectionError)?;
 
-        spawn_local_with_current_owner(send_file(connection, file.clone(), cancel_token.clone()))
-            .unwrap();
+        let peer_id = connection.peer_id();
+        let connection_cipher = peer_cipher(&connection, &cipher);
+        if blocked
+            .peer_ids
+            .with_untracked(|peer_ids| peer_ids.contains(&peer_id))
+        {
+            info!("Turned away connection from blocked peer {peer_id}");
+            spawn_local(turn_away(
+                connection,
+                connection_cipher,
+                "Blocked by the sender",
+                alive_tx.clone(),
+            ));
+            continue;
+        }
+        if connection.label() == CHUNKS_LABEL {
+            let sent = extra_txs
+                .get(&peer_id)
+                .is_some_and(|extra_tx| extra_tx.send(connection).is_ok());
+            if !sent {
+                warn!("Extra connection from {peer_id}, which isn't connected");
+                extra_txs.remove(&peer_id);
+            }
+            continue;
+        }
+
+        if let Err(reason) = attempts.record(&peer_id, Date::now()) {
+            warn!("Turned away connection from {peer_id}: {reason}");
+            spawn_local(turn_away(
+                connection,
+                connection_cipher,
+                reason,
+                alive_tx.clone(),
+            ));
+            continue;
+        }
+
+        let (extra_tx, extra_rx) = mpsc::unbounded_channel();
+        extra_txs.insert(peer_id, extra_tx);
+        spawn_local_with_current_owner(send_file(
+            connection,
+            payload.clone(),
+            extra_rx,
+            queue.clone(),
+            swarm,
+            alive_tx.clone(),
+            cancel_token.clone(),
+        ))
+        .unwrap();
+    }
+}
+
+impl Attempts {
+    fn new() -> Attempts {
+        Attempts {
+            by_peer: HashMap::new(),
+            all: VecDeque::new(),
+            backoff_until: 0.0,
+            backoff: MIN_BACKOFF,
+        }
+    }
+
+    /// Records a connection attempt, or returns why it's turned away
+    fn record(&mut self, peer_id: &str, now: f64) -> Result<(), &'static str> {
+        self.all.retain(|attempt| now - attempt < ATTEMPT_WINDOW);
+        self.by_peer.retain(|_, peer_attempts| {
+            peer_attempts.retain(|attempt| now - attempt < ATTEMPT_WINDOW);
+            !peer_attempts.is_empty()
+        });
+        if now < self.backoff_until {
+            return Err("Too many connections, try again later");
+        }
+        // The back-off only keeps growing while the limit is hit again soon after
+        if now >= self.backoff_until + ATTEMPT_WINDOW {
+            self.backoff = MIN_BACKOFF;
+        }
+        if self.all.len() >= MAX_SHARE_ATTEMPTS {
+            // The back-off stands in for the attempts that led to it
+            self.all.clear();
+            self.backoff_until = now + self.backoff;
+            self.backoff = (self.backoff * 2.0).min(MAX_BACKOFF);
+            return Err("Too many connections, try again later");
+        }
+        let peer_attempts = self.by_peer.entry(peer_id.to_string()).or_default();
+        if peer_attempts.len() >= MAX_ATTEMPTS {
+            return Err("Connecting too often, try again later");
+        }
+
+        peer_attempts.push_back(now);
+        self.all.push_back(now);
+
+        Ok(())
+    }
+}
+
+/// Cipher the peer's control connection is encrypted with until the password exchange, which
+/// depends on whether its link has the key
+fn peer_cipher(connection: &DataConnection, cipher: &Cipher) -> Cipher {
+    if connection.label() == KEYLESS_CONTROL_LABEL {
+        Cipher::keyless()
+    } else {
+        cipher.clone()
+    }
+}
+
+/// Tells a rejected peer why, then closes the connection
+async fn turn_away(
+    connection: DataConnection,
+    cipher: Cipher,
+    reason: &'static str,
+    _alive_tx: mpsc::Sender<()>,
+) {
+    let mut link = Link::new(connection, "receiver");
+    if let Ok(Ok(())) = timeout(CONNECT_TIMEOUT, link.control().wait_for_open()).await {
+        refuse(&mut link, &cipher, reason).await;
+    }
+}
+
+/// Tells the peer why it's turned away, then waits for it to close the connection, as closing
+/// straight away could lose the message
+async fn refuse(link: &mut Link, cipher: &Cipher, reason: &str) {
+    send_message(link, cipher, &Message::Error(reason.to_string()));
+    let _ = timeout(CONNECT_TIMEOUT, link.control().wait_for_close()).await;
+}
+
+impl SharePolicy {
+    fn exhausted(&self) -> Option<ShareEnd> {
+        let downloads = self.downloads.get_untracked();
+        if self.burn.get_untracked() && downloads > 0 {
+            Some(ShareEnd::Burned)
+        } else if self
+            .max_downloads
+            .get_untracked()
+            .is_some_and(|max_downloads| downloads >= max_downloads)
+        {
+            Some(ShareEnd::DownloadLimit)
+        } else if self
+            .expires_at
+            .get_untracked()
+            .is_some_and(|expires_at| Date::now() >= expires_at)
+        {
+            Some(ShareEnd::Expired)
+        } else {
+            None
+        }
+    }
+
+    /// Counts a peer that has been sent everything, closing the share if that was the last
+    /// download it allows
+    fn count_download(&self) {
+        self.downloads.update(|downloads| *downloads += 1);
+        if let Some(end) = self.exhausted() {
+            self.end(end);
+        }
+    }
+
+    /// Closes the share, which disconnects every peer and the PeerJS client
+    fn end(&self, end: ShareEnd) {
+        if self.ended.get_untracked().is_some() {
+            return;
+        }
+        self.ended.set(Some(end));
+        update_peer_status(end);
+        self.cancel_token.with_value(CancellationToken::cancel);
+    }
+}
+
+/// Closes the share once its expiry passes, or its download limit is lowered to what's already
+/// been downloaded
+async fn enforce_policy(policy: SharePolicy) {
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        if let Some(end) = policy.exhausted() {
+            policy.end(end);
+            return;
+        }
     }
 }
 
 async fn send_file(
     data_connection: DataConnection,
-    file: File,
+    payload: Payload,
+    extra_rx: mpsc::UnboundedReceiver<DataConnection>,
+    queue: UploadQueue,
+    swarm: Option<Swarm>,
+    _alive_tx: mpsc::Sender<()>,
     peer_cancel_token: CancellationToken,
 ) {
     let status = create_rw_signal("Accepting connection".to_string());
+    let (session, requests) = Session::new("receiver", data_connection.peer_id());
     let connection = Connection {
         id: Uuid::new_v4(),
         peer_id: data_connection.peer_id(),
         status,
+        session,
+        swarm,
+        keyless: data_connection.label() == KEYLESS_CONTROL_LABEL,
+        disconnect: peer_cancel_token.child_token(),
+        fingerprint: create_rw_signal(None),
+        verification: create_rw_signal(None),
+        approval: create_rw_signal(None),
+        confirmation: create_rw_signal(None),
+        downloaded: create_rw_signal(false),
     };
 
     let set_connections = use_context::<WriteSignal<Vec<Connection>>>().unwrap();
     set_connections.update(|connections| {
-        connections.insert(0, connection);
+        connections.insert(0, connection.clone());
     });
 
+    let mut cipher = peer_cipher(&data_connection, &use_context::<Cipher>().unwrap());
+    let policy = use_context::<SharePolicy>().unwrap();
+    let mut link = Link::new(data_connection, "receiver");
+
     let result = select! {
-        v = send_file_inner(data_connection, file, status) => v,
-        _ = peer_cancel_token.cancelled() => {
-            return;
+        v = send_file_inner(
+            &mut link, payload, requests, extra_rx, &queue, &mut cipher, &connection,
+        ) => v,
+        _ = connection.disconnect.cancelled() => {
+            // Lets the receiver tell the share was closed or it was turned away, rather than the
+            // connection dropping
+            let ended = policy.ended.try_get_untracked().flatten();
+            send_message(&link, &cipher, &ended.map_or(Message::Cancel, Message::Ended));
+            // Dropping the link straight away could lose the message. The peer closes the
+            // connection once it's read it
+            let _ = timeout(CONNECT_TIMEOUT, link.control().wait_for_close()).await;
+            if let Some(end) = ended {
+                // The peer whose download closed the share still got everything
+                if connection.downloaded.get_untracked() {
+                    update_connection_status(status, "Disconnected");
+                } else {
+                    update_connection_status(status, end);
+                }
+                return;
+            }
+            if peer_cancel_token.is_cancelled() {
+                return;
+            }
+            let blocked = use_context::<Blocked>()
+                .unwrap()
+                .peer_ids
+                .with_untracked(|peer_ids| peer_ids.contains(&connection.peer_id));
+            Err(if blocked {
+                ConnectionError::Blocked
+            } else {
+                ConnectionError::Disconnected
+            })
         },
     };
 
+    // Later receivers aren't pointed at a peer that's gone
+    if let Some(swarm) = swarm {
+        swarm.leave(&connection.peer_id);
+    }
+
     if let Err(error) = result {
         update_connection_status(status, error.to_string());
     }
 }
 
 async fn send_file_inner(
-    mut data_connection: DataConnection,
-    file: File,
-    status: RwSignal<String>,
-) -> Result<(), SendFileError> {
-    timeout(CONNECT_TIMEOUT, data_connection.wait_for_open())
+    link: &mut Link,
+    payload: Payload,
+    requests: Requests,
+    mut extra_rx: mpsc::UnboundedReceiver<DataConnection>,
+    queue: &UploadQueue,
+    cipher: &mut Cipher,
+    connection: &Connection,
+) -> Result<(), ConnectionError> {
+    let Connection {
+        status, session, ..
+    } = *connection;
+
+    timeout(CONNECT_TIMEOUT, link.control().wait_for_open())
         .await
-        .map_err(|_| SendFileError::OpenDataConnectionTimedOut)?
-        .map_err(SendFileError::OpenDataConnectionError)?;
+        .map_err(|_| ConnectionError::OpenDataConnectionTimedOut)?
+        .map_err(ConnectionError::OpenDataConnectionError)?;
 
-    update_connection_status(
-        status,
-        format!(
-            "Connection from {}. Sending file",
-            data_connection.peer_id()
-        ),
+    let fingerprints = link.control().fingerprints();
+    let phrase = fingerprints
+        .as_ref()
+        .map(|(local, remote)| verification_phrase(local, remote));
+    connection.verification.set(phrase.clone());
+    if let Some((_, remote)) = fingerprints {
+        let blocked = use_context::<Blocked>().unwrap();
+        if blocked
+            .fingerprints
+            .with_untracked(|fingerprints| fingerprints.contains(&remote))
+        {
+            refuse(link, cipher, "Blocked by the sender").await;
+            return Err(ConnectionError::Blocked);
+        }
+        connection.fingerprint.set(Some(remote));
+    }
+
+    let settings = use_context::<ReadSignal<Rc<Settings>>>()
+        .unwrap()
+        .get_untracked();
+
+    let capabilities = match connection.swarm {
+        Some(_) => &[CAPABILITY_SWARM][..],
+        None => &[],
+    };
+    let peer = handshake(link, cipher, capabilities)
+        .await
+        .map_err(ConnectionError::HandshakeError)?;
+    let connections = negotiate_connections(link, cipher, settings.connections.get_untracked())
+        .await
+        .map_err(ConnectionError::HandshakeError)?;
+    if connections > 1 {
+        update_connection_status(status, format!("Opening {connections} connections"));
+        // The peer opens the extra connections straight after agreeing to them
+        for _ in 1..connections {
+            let mut extra = timeout(CONNECT_TIMEOUT, extra_rx.recv())
+                .await
+                .ok()
+                .flatten()
+                .ok_or(ConnectionError::OpenDataConnectionTimedOut)?;
+            timeout(CONNECT_TIMEOUT, extra.wait_for_open())
+                .await
+                .map_err(|_| ConnectionError::OpenDataConnectionTimedOut)?
+                .map_err(ConnectionError::OpenDataConnectionError)?;
+            link.add_connection(extra);
+        }
+    }
+
+    let password = use_context::<SharePassword>().unwrap().0.get_untracked();
+    if password.is_empty() && connection.keyless {
+        refuse(
+            link,
+            cipher,
+            "The share has no password, so the link needs its key",
+        )
+        .await;
+        return Err(ConnectionError::KeyRequired);
+    }
+    send_message(
+        link,
+        cipher,
+        &Message::PasswordRequired(!password.is_empty()),
     );
-    info!("Connection from {}", data_connection.peer_id());
+    if !password.is_empty() {
+        let WrongPasswords(wrong_passwords) = use_context::<WrongPasswords>().unwrap();
+        let now = Date::now();
+        wrong_passwords.update_value(|wrong_passwords| {
+            wrong_passwords.retain(|wrong| now - wrong < WRONG_PASSWORD_WINDOW);
+        });
+        if wrong_passwords.with_value(VecDeque::len) >= MAX_WRONG_PASSWORDS {
+            refuse(link, cipher, "Too many wrong passwords, try again later").await;
+            return Err(ConnectionError::TooManyWrongPasswords);
+        }
 
-    data_connection.send_string(&file.name());
-    data_connection.send(&file.slice().unwrap());
+        update_connection_status(status, "Waiting for the peer's password");
+        *cipher = exchange_password(link, cipher, &password)
+            .await
+            // A peer that drops the connection instead of confirming has still had its guess
+            // checked, so any failure counts
+            .inspect_err(|_| {
+                wrong_passwords.update_value(|wrong_passwords| {
+                    wrong_passwords.push_back(Date::now());
+                });
+            })
+            .map_err(ConnectionError::PasswordError)?;
+    }
 
-    update_connection_status(status, "File sent. Waiting for confirmation");
+    if settings.require_approval.get_untracked() {
+        approve_connection(link, cipher, connection).await?;
+    }
+    if settings.confirm_verification.get_untracked() {
+        if phrase.is_none() {
+            return Err(ConnectionError::VerificationUnavailable);
+        }
+        confirm_phrase(connection).await?;
+    }
 
-    data_connection
-        .wait_for_close()
-        .await
-        .map_err(SendFileError::CloseError)?;
+    update_connection_status(status, format!("Connected to {}", connection.peer_id));
+    info!("Connection from {}", connection.peer_id);
 
-    update_connection_status(status, "Done");
+    // The shared files or text are offered first, and sent once it's the peer's turn in the
+    // queue. Then either side can offer more
+    let note = use_context::<ShareNote>().unwrap().0.get_untracked();
+    let member = connection
+        .swarm
+        .map(|swarm| SwarmMember::new(swarm, connection.peer_id.clone()));
+    let sent_rx = session.offer(
+        payload,
+        (!note.trim().is_empty()).then_some(note),
+        Some(queue.clone()),
+        member,
+    );
+    let policy = use_context::<SharePolicy>().unwrap();
+    let downloaded = connection.downloaded;
+    let count_download = async move {
+        if sent_rx.await.is_ok() {
+            downloaded.set(true);
+            policy.count_download();
+        }
+    };
+
+    let (result, ()) = join!(
+        run_session(link, cipher, session, requests, &peer, false),
+        count_download
+    );
+    result.map_err(ConnectionError::SessionError)?;
+
+    update_connection_status(status, "Disconnected");
 
     Ok(())
 }
 
+/// Waits for the user to approve the connection. A denied peer is told so before the connection
+/// closes
+async fn approve_connection(
+    link: &mut Link,
+    cipher: &Cipher,
+    connection: &Connection,
+) -> Result<(), ConnectionError> {
+    update_connection_status(connection.status, "Waiting for approval");
+
+    let (approve_tx, mut approve_rx) = mpsc::channel(1);
+    connection.approval.set(Some(approve_tx));
+
+    match approve_rx.recv().await {
+        Some(true) => Ok(()),
+        Some(false) => {
+            send_message(link, cipher, &Message::Deny);
+            let _ = timeout(CONNECT_TIMEOUT, link.control().wait_for_close()).await;
+            Err(ConnectionError::Denied)
+        }
+        None => Err(ConnectionError::ApprovalClosed),
+    }
+}
+
+/// Waits for the user to compare the verification phrase with the receiver's
+async fn confirm_phrase(connection: &Connection) -> Result<(), ConnectionError> {
+    update_connection_status(
+        connection.status,
+        "Waiting for the verification phrase to be confirmed",
+    );
+
+    let (confirm_tx, mut confirm_rx) = mpsc::channel(1);
+    connection.confirmation.set(Some(confirm_tx));
+
+    match confirm_rx.recv().await {
+        Some(true) => Ok(()),
+        Some(false) => Err(ConnectionError::VerificationRejected),
+        None => Err(ConnectionError::ConfirmationClosed),
+    }
+}
+
 fn update_peer_status<T: ToString>(message: T) {
     let message = message.to_string();
 
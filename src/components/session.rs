@@ -0,0 +1,448 @@
+use std::{collections::VecDeque, rc::Rc};
+
+use js_sys::Date;
+use leptos::{html::Input, *};
+use log::error;
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+};
+use uuid::Uuid;
+use web_sys::{Event, MouseEvent};
+
+use crate::{
+    components::{
+        controls::{Cancelled, Control, Controls, Paused},
+        incoming::{incoming_view, receive_files, Incoming, ReceiveFileError, ReceiveOutcome},
+        outgoing::{
+            outgoing_view, send_files, share_files, Offer, Outgoing, Payload, SendFileError,
+            SendOutcome,
+        },
+        swarm::SwarmMember,
+    },
+    crypto::Cipher,
+    files::{self, SelectedFile},
+    history::{HistoryDirection, HistoryEntry, HistoryStore, Outcome},
+    peerjs::dataconnection::DataConnectionError,
+    protocol::{
+        receive_message, send_message, FileHeader, Hello, Link, Manifest, Message, ProtocolError,
+        ShareEnd, CAPABILITY_SWARM,
+    },
+    queue::UploadQueue,
+};
+
+/// Files and text sent both ways over one connection. Either peer can offer files once connected,
+/// and offers are sent one at a time in the order they were made
+#[derive(Clone, Copy)]
+pub(crate) struct Session {
+    transfers: RwSignal<Vec<Transfer>>,
+    offer_tx: StoredValue<mpsc::UnboundedSender<Offer>>,
+    control_tx: StoredValue<mpsc::UnboundedSender<Control>>,
+    paused: RwSignal<Option<Paused>>,
+    /// How the peer is described to the user, such as "sender"
+    peer_name: &'static str,
+    /// Recorded in the transfer history
+    peer_id: StoredValue<String>,
+}
+
+/// What the user asked of the session, waiting for `run_session` to act on it
+pub(crate) struct Requests {
+    offer_rx: mpsc::UnboundedReceiver<Offer>,
+    controls: Controls,
+}
+
+#[derive(Clone)]
+struct Transfer {
+    id: Uuid,
+    direction: Direction,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Incoming(Incoming),
+    Outgoing(Outgoing),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum SessionError {
+    #[error("{0}")]
+    SendFileError(SendFileError),
+    #[error("{0}")]
+    ReceiveFileError(ReceiveFileError),
+    #[error("Error while waiting for an offer: {0}")]
+    ReceiveOfferError(ProtocolError),
+    #[error("The sender denied the connection")]
+    Denied,
+    #[error("{0}")]
+    ShareEnded(ShareEnd),
+    #[error("{0}")]
+    Cancelled(Cancelled),
+    #[error("Expected an offer but received a different message")]
+    UnexpectedMessage,
+}
+
+impl Session {
+    pub fn new(peer_name: &'static str, peer_id: String) -> (Session, Requests) {
+        let (offer_tx, offer_rx) = mpsc::unbounded_channel();
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let paused = create_rw_signal(None);
+        let session = Session {
+            transfers: create_rw_signal(Vec::new()),
+            offer_tx: store_value(offer_tx),
+            control_tx: store_value(control_tx),
+            paused,
+            peer_name,
+            peer_id: store_value(peer_id),
+        };
+        let requests = Requests {
+            offer_rx,
+            controls: Controls::new(control_rx, paused),
+        };
+
+        (session, requests)
+    }
+
+    /// Queues files or text that are ready to be offered. With an upload queue, the files wait
+    /// their turn in it once the peer accepts them. With a swarm member, the peer can fetch chunks from other
+    /// receivers. The returned receiver fires once the peer has everything
+    pub fn offer(
+        &self,
+        payload: Payload,
+        note: Option<String>,
+        queue: Option<UploadQueue>,
+        swarm: Option<SwarmMember>,
+    ) -> oneshot::Receiver<()> {
+        let (sent_tx, sent_rx) = oneshot::channel();
+        let outgoing = Outgoing::new("Waiting to offer");
+        outgoing.set_payload(&payload);
+        self.add_transfer(Direction::Outgoing(outgoing));
+
+        self.send_offer(Offer {
+            payload,
+            note,
+            outgoing,
+            offered: false,
+            queue,
+            swarm,
+            sent_tx: Some(sent_tx),
+        });
+
+        sent_rx
+    }
+
+    /// Hashes the files, then queues them to be offered
+    fn offer_selected(&self, files: Vec<SelectedFile>) {
+        let outgoing = Outgoing::new("Hashing");
+        self.add_transfer(Direction::Outgoing(outgoing));
+
+        let session = *self;
+        spawn_local(async move {
+            let file_count = files.len();
+            let shared_files = share_files(files, |path, i| {
+                outgoing
+                    .status
+                    .set(format!("Hashing {path} ({}/{file_count})", i + 1));
+            })
+            .await;
+
+            match shared_files {
+                Ok(shared_files) => {
+                    let payload = Payload::Files(Rc::new(shared_files));
+                    outgoing.set_payload(&payload);
+                    outgoing.status.set("Waiting to offer".to_string());
+                    session.send_offer(Offer {
+                        payload,
+                        note: None,
+                        outgoing,
+                        offered: false,
+                        queue: None,
+                        swarm: None,
+                        sent_tx: None,
+                    });
+                }
+                Err(error) => outgoing.status.set(error.to_string()),
+            }
+        });
+    }
+
+    fn control(&self, control: Control) {
+        self.control_tx.with_value(|control_tx| {
+            let _ = control_tx.send(control);
+        });
+    }
+
+    fn send_offer(&self, offer: Offer) {
+        self.offer_tx.with_value(|offer_tx| {
+            let _ = offer_tx.send(offer);
+        });
+    }
+
+    /// Adds an entry to the transfer history for each file. Failing to is only logged, so it never
+    /// gets in the way of the transfer
+    fn record(
+        &self,
+        files: &[FileHeader],
+        direction: HistoryDirection,
+        started: f64,
+        result: Result<Outcome, String>,
+    ) {
+        let ended = Date::now();
+        let peer_id = self.peer_id.get_value();
+        let entries = files
+            .iter()
+            .map(|header| {
+                HistoryEntry::new(header, direction, &peer_id, started, ended, result.clone())
+            })
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            return;
+        }
+
+        spawn_local(async move {
+            let result = match HistoryStore::open().await {
+                Ok(store) => store.add(&entries).await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                error!("Error recording transfer history: {error}");
+            }
+        });
+    }
+
+    fn add_transfer(&self, direction: Direction) {
+        self.transfers.update(|transfers| {
+            transfers.push(Transfer {
+                id: Uuid::new_v4(),
+                direction,
+            })
+        });
+    }
+}
+
+pub(crate) fn session_view(session: Session) -> impl IntoView {
+    let file_input_ref = create_node_ref::<Input>();
+
+    let send_click = move |_: MouseEvent| {
+        if let Some(e) = file_input_ref() {
+            spawn_local(async move {
+                e.click();
+            });
+        }
+    };
+
+    let on_file_input_change = move |_: Event| {
+        let Some(input) = file_input_ref() else {
+            return;
+        };
+        let Some(file_list) = input.files() else {
+            return;
+        };
+
+        let files = files::from_file_list(&file_list);
+        // Cleared so picking the same files again still fires a change
+        input.set_value("");
+        if !files.is_empty() {
+            session.offer_selected(files);
+        }
+    };
+
+    let paused = session.paused;
+    let paused_view = move || {
+        paused.get().map(|paused| match paused {
+            Paused::Here => "Paused".to_string(),
+            Paused::ByPeer => format!("Paused by {}", session.peer_name),
+        })
+    };
+    let pause_click = move |_: MouseEvent| {
+        session.control(match paused.get_untracked() {
+            Some(_) => Control::Resume,
+            None => Control::Pause,
+        })
+    };
+
+    view! {
+        <div>
+            <div>{paused_view}</div>
+            <div on:click=pause_click>{move || if paused.get().is_some() { "Resume" } else { "Pause" }}</div>
+            <div on:click=move |_| session.control(Control::Cancel)>"Cancel"</div>
+            <div on:click=send_click>"Send files"</div>
+            <input type="file" multiple hidden node_ref=file_input_ref on:change=on_file_input_change/>
+            <For
+                each=move || session.transfers.get()
+                key=|transfer| transfer.id
+                children=transfer_view
+            />
+        </div>
+    }
+}
+
+fn transfer_view(transfer: Transfer) -> impl IntoView {
+    match transfer.direction {
+        Direction::Incoming(incoming) => view! {
+            <div>
+                <div>"From peer"</div>
+                {incoming_view(incoming)}
+            </div>
+        },
+        Direction::Outgoing(outgoing) => view! {
+            <div>
+                <div>"To peer"</div>
+                {outgoing_view(outgoing)}
+            </div>
+        },
+    }
+}
+
+/// Sends queued offers and receives the peer's until the connection closes. When both peers
+/// offer at once, the one that started the connection gives way. If the session fails, the peer
+/// is told why, unless the user cancelled it
+pub(crate) async fn run_session(
+    link: &mut Link,
+    cipher: &Cipher,
+    session: Session,
+    mut requests: Requests,
+    peer: &Hello,
+    started_connection: bool,
+) -> Result<(), SessionError> {
+    exchange_offers(
+        link,
+        cipher,
+        session,
+        &mut requests,
+        peer,
+        started_connection,
+    )
+    .await
+    .inspect_err(|error| {
+        if !requests.controls.is_cancelled() {
+            send_message(link, cipher, &Message::Error(error.to_string()));
+        }
+    })
+}
+
+async fn exchange_offers(
+    link: &mut Link,
+    cipher: &Cipher,
+    session: Session,
+    requests: &mut Requests,
+    peer: &Hello,
+    started_connection: bool,
+) -> Result<(), SessionError> {
+    let Requests { offer_rx, controls } = requests;
+
+    let mut pending = VecDeque::new();
+    // The peer's offer that crossed one of ours, when ours didn't give way
+    let mut crossed = None;
+    // Set once an offer from the peer has been received and confirmed
+    let mut received = false;
+
+    loop {
+        if let Some(manifest) = crossed.take() {
+            received |= receive_offer(link, cipher, session, manifest, controls).await?;
+            continue;
+        }
+
+        if let Some(mut offer) = pending.pop_front() {
+            // Only peers that pass chunks on are let into the swarm
+            if !peer.supports(CAPABILITY_SWARM) {
+                offer.swarm = None;
+            }
+            let started = Date::now();
+            let result = send_files(
+                link,
+                cipher,
+                &offer,
+                started_connection,
+                &mut crossed,
+                controls,
+            )
+            .await
+            .inspect_err(|error| offer.outgoing.status.set(error.to_string()));
+            let finished = match &result {
+                Ok(SendOutcome::Sent) => Some(Ok(Outcome::Completed)),
+                Ok(SendOutcome::Declined) => Some(Ok(Outcome::Declined)),
+                Ok(SendOutcome::GaveWay(_)) => None,
+                Err(error) => Some(Err(error.to_string())),
+            };
+            if let (Some(result), Payload::Files(files)) = (finished, &offer.payload) {
+                let headers = files
+                    .iter()
+                    .map(|file| file.header.clone())
+                    .collect::<Vec<_>>();
+                session.record(&headers, HistoryDirection::Sent, started, result);
+            }
+            match result.map_err(SessionError::SendFileError)? {
+                SendOutcome::Sent => {
+                    if let Some(sent_tx) = offer.sent_tx.take() {
+                        let _ = sent_tx.send(());
+                    }
+                }
+                SendOutcome::Declined => {}
+                SendOutcome::GaveWay(manifest) => {
+                    offer.offered = true;
+                    pending.push_front(offer);
+                    received |= receive_offer(link, cipher, session, manifest, controls).await?;
+                }
+            }
+            continue;
+        }
+
+        select! {
+            biased;
+            Some(offer) = offer_rx.recv() => pending.push_back(offer),
+            Some(control) = controls.recv() => {
+                controls
+                    .apply(link, cipher, control)
+                    .map_err(SessionError::Cancelled)?;
+            }
+            message = receive_message(link, cipher) => match message {
+                Ok(Message::Manifest(manifest)) => {
+                    received |= receive_offer(link, cipher, session, manifest, controls).await?;
+                }
+                Ok(Message::Deny) => return Err(SessionError::Denied),
+                Ok(Message::Pause) => controls.peer_paused(true),
+                Ok(Message::Resume) => controls.peer_paused(false),
+                Ok(_) => return Err(SessionError::UnexpectedMessage),
+                Err(ProtocolError::DataConnectionError(DataConnectionError::Closed)) => {
+                    return Ok(());
+                }
+                // Such as when the download just received used up the share, so it closed
+                Err(ProtocolError::ShareEnded(_)) if received => return Ok(()),
+                Err(ProtocolError::ShareEnded(end)) => return Err(SessionError::ShareEnded(end)),
+                Err(error) => return Err(SessionError::ReceiveOfferError(error)),
+            },
+        }
+    }
+}
+
+/// Receives the peer's offer. Returns whether it was accepted and received
+async fn receive_offer(
+    link: &mut Link,
+    cipher: &Cipher,
+    session: Session,
+    manifest: Manifest,
+    controls: &mut Controls,
+) -> Result<bool, SessionError> {
+    let incoming = Incoming::new();
+    session.add_transfer(Direction::Incoming(incoming));
+
+    let started = Date::now();
+    let files = manifest.files.clone();
+    let result = receive_files(link, cipher, manifest, incoming, controls)
+        .await
+        .inspect_err(|error| incoming.status.set(error.to_string()));
+    session.record(
+        &files,
+        HistoryDirection::Received,
+        started,
+        match &result {
+            Ok(ReceiveOutcome::Received) => Ok(Outcome::Completed),
+            Ok(ReceiveOutcome::Declined) => Ok(Outcome::Declined),
+            Err(error) => Err(error.to_string()),
+        },
+    );
+
+    result
+        .map(|outcome| matches!(outcome, ReceiveOutcome::Received))
+        .map_err(SessionError::ReceiveFileError)
+}
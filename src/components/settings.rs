@@ -3,18 +3,20 @@ use std::{rc::Rc, time::Duration};
 use gloo_utils::format::JsValueSerdeExt;
 use js_sys::JSON;
 use leptos::{
-    component, create_memo, create_rw_signal, event_target_value, set_interval, use_context, view,
-    window, For, IntoView, ReadSignal, RwSignal, SignalGet, SignalGetUntracked, SignalSet,
-    SignalUpdate, WriteSignal,
+    component, create_memo, create_rw_signal, event_target_checked, event_target_value,
+    set_interval, use_context, view, window, For, IntoView, ReadSignal, RwSignal, SignalGet,
+    SignalGetUntracked, SignalSet, SignalUpdate, WriteSignal,
 };
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use wasm_bindgen::JsValue;
 
-use crate::{peerjs::ICEServer, utils::jserror};
+use crate::{peerjs::ICEServer, protocol::MAX_CONNECTIONS, utils::jserror};
 
 const SETTINGS_KEY: &str = "settings";
+const DEFAULT_CONNECTIONS: u32 = 4;
+const DEFAULT_MAX_UPLOADS: u32 = 3;
 
 #[component]
 pub(crate) fn SettingsEditor() -> impl IntoView {
@@ -53,6 +55,60 @@ pub(crate) fn SettingsEditor() -> impl IntoView {
                 set_settings(Rc::new(Settings::default()));
                 info!("Settings reset");
             }>"Reset"</div>
+            <label>
+                <input
+                    type="checkbox"
+                    prop:checked=move || settings.get().require_approval.get()
+                    on:change=move |event| settings.get_untracked().require_approval.set(event_target_checked(&event))
+                />
+                "Approve each connection before sending"
+            </label>
+            <label>
+                <input
+                    type="checkbox"
+                    prop:checked=move || settings.get().confirm_verification.get()
+                    on:change=move |event| settings.get_untracked().confirm_verification.set(event_target_checked(&event))
+                />
+                "Hold files until the verification phrase is confirmed"
+            </label>
+            <label>
+                <input
+                    type="number"
+                    min="1"
+                    max=MAX_CONNECTIONS
+                    prop:value=move || settings.get().connections.get().to_string()
+                    on:change=move |event| {
+                        if let Ok(connections) = event_target_value(&event).parse::<u32>() {
+                            settings
+                                .get_untracked()
+                                .connections
+                                .set(connections.clamp(1, MAX_CONNECTIONS));
+                        }
+                    }
+                />
+                "Parallel connections per peer"
+            </label>
+            <label>
+                <input
+                    type="number"
+                    min="1"
+                    prop:value=move || settings.get().max_uploads.get().to_string()
+                    on:change=move |event| {
+                        if let Ok(max_uploads) = event_target_value(&event).parse::<u32>() {
+                            settings.get_untracked().max_uploads.set(max_uploads.max(1));
+                        }
+                    }
+                />
+                "Peers to send to at once, the rest wait in a queue"
+            </label>
+            <label>
+                <input
+                    type="checkbox"
+                    prop:checked=move || settings.get().swarm.get()
+                    on:change=move |event| settings.get_untracked().swarm.set(event_target_checked(&event))
+                />
+                "Swarm mode: receivers of a share pass chunks on to each other"
+            </label>
             <div>"Servers"</div>
             <div on:click=on_add_click>"Add"</div>
             <For
@@ -130,6 +186,17 @@ fn server_view(server: Rc<SettingsServer>) -> impl IntoView {
 
 pub struct Settings {
     pub servers: RwSignal<Vec<Rc<SettingsServer>>>,
+    /// Whether the sender waits for the user to approve each connection
+    pub require_approval: RwSignal<bool>,
+    /// Whether the sender waits for the user to confirm each connection's verification phrase
+    pub confirm_verification: RwSignal<bool>,
+    /// How many connections to open to each peer to spread chunks across. The peer may agree to
+    /// fewer
+    pub connections: RwSignal<u32>,
+    /// How many peers are sent a share at once. Later peers wait their turn
+    pub max_uploads: RwSignal<u32>,
+    /// Whether a share's receivers fetch chunks from each other, and pass on the chunks they have
+    pub swarm: RwSignal<bool>,
 }
 
 #[derive(PartialEq)]
@@ -144,6 +211,16 @@ pub struct SettingsServer {
 #[derive(Serialize, Deserialize)]
 struct SavedSettings {
     servers: Vec<SavedSettingsServer>,
+    #[serde(default)]
+    require_approval: bool,
+    #[serde(default)]
+    confirm_verification: bool,
+    #[serde(default = "default_connections")]
+    connections: u32,
+    #[serde(default = "default_max_uploads")]
+    max_uploads: u32,
+    #[serde(default)]
+    swarm: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -163,6 +240,11 @@ impl Default for Settings {
                 credential: RwSignal::new(String::new()),
                 editing: RwSignal::new(false),
             })]),
+            require_approval: create_rw_signal(false),
+            confirm_verification: create_rw_signal(false),
+            connections: create_rw_signal(DEFAULT_CONNECTIONS),
+            max_uploads: create_rw_signal(DEFAULT_MAX_UPLOADS),
+            swarm: create_rw_signal(false),
         }
     }
 }
@@ -219,6 +301,11 @@ impl Settings {
                     credential: string_to_option(server.credential.get_untracked()),
                 })
                 .collect(),
+            require_approval: self.require_approval.get_untracked(),
+            confirm_verification: self.confirm_verification.get_untracked(),
+            connections: self.connections.get_untracked(),
+            max_uploads: self.max_uploads.get_untracked(),
+            swarm: self.swarm.get_untracked(),
         }
     }
 }
@@ -241,6 +328,11 @@ impl From<SavedSettings> for Settings {
                     })
                     .collect(),
             ),
+            require_approval: create_rw_signal(value.require_approval),
+            confirm_verification: create_rw_signal(value.confirm_verification),
+            connections: create_rw_signal(value.connections.clamp(1, MAX_CONNECTIONS)),
+            max_uploads: create_rw_signal(value.max_uploads.max(1)),
+            swarm: create_rw_signal(value.swarm),
         }
     }
 }
@@ -255,6 +347,14 @@ impl SettingsServer {
     }
 }
 
+fn default_connections() -> u32 {
+    DEFAULT_CONNECTIONS
+}
+
+fn default_max_uploads() -> u32 {
+    DEFAULT_MAX_UPLOADS
+}
+
 fn string_to_option(value: String) -> Option<String> {
     if value.is_empty() {
         None
@@ -0,0 +1,504 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    future::pending,
+    rc::{Rc, Weak},
+    time::Duration,
+};
+
+use js_sys::Date;
+use leptos::*;
+use log::{error, info, warn};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+};
+use uuid::Uuid;
+
+use crate::{
+    components::{app::CONNECT_TIMEOUT, progress::Progress},
+    compression::Codec,
+    crypto::{Cipher, ShareKey},
+    files::read_slice,
+    idb::IdbError,
+    merkle,
+    partial::{PartialStore, PartialTransfer},
+    peerjs::{
+        client::Client,
+        dataconnection::{DataConnection, DataConnectionError},
+        peerid::PeerID,
+    },
+    protocol::{
+        receive_message, send_chunk, send_message, Chunk, FileHeader, Link, Message, ProtocolError,
+        SeedRequest, Seeder, MAX_SEED_CHUNKS, SEED_LABEL,
+    },
+    utils::{jserror, sleep, timeout},
+};
+
+/// How often a seeding page confirms it's still passing on the files it keeps
+const SEED_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+/// How long after its last confirmation a page's kept files are taken as left behind
+const SEED_STALE_AFTER: f64 = 5.0 * 60_000.0;
+
+/// Which receivers of a share hold which chunks, so later receivers can fetch them from each other
+/// instead of the sender
+#[derive(Clone, Copy)]
+pub(crate) struct Swarm {
+    /// Sent to receivers in the manifest, to encrypt the seed connections between them
+    key: StoredValue<ShareKey>,
+    /// Chunks each receiver holds of each file, counted from the start, by peer ID and file
+    holdings: StoredValue<HashMap<String, HashMap<u32, u64>>>,
+    /// Bytes each receiver has passed on to others, by peer ID
+    carried: RwSignal<HashMap<String, u64>>,
+    /// Bytes the sender sent itself
+    pub sent: RwSignal<u64>,
+}
+
+/// One receiver's place in the swarm
+#[derive(Clone)]
+pub(crate) struct SwarmMember {
+    swarm: Swarm,
+    peer_id: String,
+}
+
+/// A receiver's part in a swarm. It fetches chunks from receivers further ahead, and keeps its own
+/// to pass on to those behind
+#[derive(Clone)]
+pub(crate) struct Seeding {
+    state: Rc<RefCell<SeedingState>>,
+    connect_tx: mpsc::UnboundedSender<(PeerID, oneshot::Sender<DataConnection>)>,
+    /// Bytes passed on to other receivers
+    pub passed_on: RwSignal<u64>,
+}
+
+struct SeedingState {
+    /// Marks the files this page keeps in the partial store, which other pages share
+    owner: String,
+    /// Set once a share with a swarm is accepted
+    cipher: Option<Cipher>,
+    /// Partial store keys of the files kept to pass on. The saved ones are removed once the page
+    /// stops seeding, or by a later page once this one stops confirming it's seeding
+    kept: HashSet<String>,
+}
+
+/// Seed connections waiting to be opened by `serve_seeds`, which owns the PeerJS client
+pub(crate) struct SeedConnects(mpsc::UnboundedReceiver<(PeerID, oneshot::Sender<DataConnection>)>);
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum SeedError {
+    #[error("No share with a swarm has been accepted")]
+    NotSeeding,
+    #[error("Seeder has an invalid peer ID: {0}")]
+    InvalidPeerId(String),
+    #[error("Stopped opening seed connections")]
+    Stopped,
+    #[error("Error while opening seed connection: {0}")]
+    OpenError(DataConnectionError),
+    #[error("Seed connection open timed out")]
+    OpenTimedOut,
+    #[error("Error while receiving over seed connection: {0}")]
+    ReceiveError(ProtocolError),
+    #[error("Error while sending chunk: {0}")]
+    SendChunkError(DataConnectionError),
+    #[error("Expected {0} but received a different message")]
+    UnexpectedMessage(&'static str),
+    #[error("Received chunk {received} of file {file} but expected chunk {expected}")]
+    OutOfOrderChunk {
+        file: u32,
+        received: u64,
+        expected: u64,
+    },
+    #[error("Chunk {0} doesn't match the sender's Merkle root")]
+    CorruptChunk(u64),
+    #[error("Asked for a file that isn't being passed on")]
+    UnknownFile,
+    #[error("Asked for chunks that haven't been saved")]
+    MissingChunks,
+    #[error("Error while reading saved chunk {0}")]
+    ReadChunkError(u64),
+    #[error("Chunk {0} was saved without its proof")]
+    MissingProof(u64),
+    #[error("Error while accessing saved chunks: {0}")]
+    StorageError(IdbError),
+}
+
+impl Swarm {
+    pub fn new() -> Swarm {
+        Swarm {
+            key: store_value(ShareKey::generate()),
+            holdings: store_value(HashMap::new()),
+            carried: create_rw_signal(HashMap::new()),
+            sent: create_rw_signal(0),
+        }
+    }
+
+    /// Forgets what the receiver holds once it's gone. What it passed on is still counted
+    pub fn leave(&self, peer_id: &str) {
+        self.holdings.try_update_value(|holdings| {
+            holdings.remove(peer_id);
+        });
+    }
+
+    pub fn carried_by(&self, peer_id: &str) -> u64 {
+        self.carried
+            .with(|carried| carried.get(peer_id).copied().unwrap_or(0))
+    }
+
+    pub fn total_carried(&self) -> u64 {
+        self.carried.with(|carried| carried.values().sum())
+    }
+}
+
+impl SwarmMember {
+    pub fn new(swarm: Swarm, peer_id: String) -> SwarmMember {
+        SwarmMember { swarm, peer_id }
+    }
+
+    pub fn key(&self) -> ShareKey {
+        self.swarm.key.get_value()
+    }
+
+    /// Records that the receiver holds the first `chunks` chunks of the file
+    pub fn hold(&self, file: u32, chunks: u64) {
+        self.swarm.holdings.update_value(|holdings| {
+            let held = holdings
+                .entry(self.peer_id.clone())
+                .or_default()
+                .entry(file)
+                .or_default();
+            *held = (*held).max(chunks);
+        });
+    }
+
+    /// Picks the other receiver furthest into the file, if it's ahead of `from_chunk`. Returns its
+    /// peer ID and the chunk to fetch up to
+    pub fn find_seeder(&self, file: u32, from_chunk: u64) -> Option<(String, u64)> {
+        let (peer_id, held) = self.swarm.holdings.with_value(|holdings| {
+            holdings
+                .iter()
+                .filter(|(peer_id, _)| **peer_id != self.peer_id)
+                .filter_map(|(peer_id, files)| Some((peer_id.clone(), *files.get(&file)?)))
+                .max_by_key(|(_, held)| *held)
+        })?;
+
+        (held > from_chunk).then(|| (peer_id, held.min(from_chunk + MAX_SEED_CHUNKS)))
+    }
+
+    /// Counts bytes the seeder passed on to this receiver
+    pub fn credit(&self, seeder: &str, bytes: u64) {
+        self.swarm.carried.update(|carried| {
+            *carried.entry(seeder.to_string()).or_default() += bytes;
+        });
+    }
+
+    /// Counts bytes the sender sent this receiver itself
+    pub fn count_sent(&self, bytes: u64) {
+        self.swarm.sent.update(|sent| *sent += bytes);
+    }
+}
+
+impl Seeding {
+    pub fn new() -> (Seeding, SeedConnects) {
+        let (connect_tx, connect_rx) = mpsc::unbounded_channel();
+        let seeding = Seeding {
+            state: Rc::new(RefCell::new(SeedingState {
+                owner: Uuid::new_v4().to_string(),
+                cipher: None,
+                kept: HashSet::new(),
+            })),
+            connect_tx,
+            passed_on: create_rw_signal(0),
+        };
+        spawn_local(heartbeat(Rc::downgrade(&seeding.state)));
+
+        (seeding, SeedConnects(connect_rx))
+    }
+
+    /// Joins the swarm of an accepted share
+    pub fn join(&self, key: &ShareKey) {
+        self.state.borrow_mut().cipher = Some(key.cipher());
+    }
+
+    /// Keeps the file's chunks once it's saved, and lets other receivers fetch them
+    pub fn keep(&self, key: &str) {
+        self.state.borrow_mut().kept.insert(key.to_string());
+    }
+
+    /// Stops passing on the file, such as when its chunks were thrown away
+    pub fn release(&self, key: &str) {
+        self.state.borrow_mut().kept.remove(key);
+    }
+
+    /// Identifies this page in the partial store entries of the files it keeps
+    pub fn owner(&self) -> String {
+        self.state.borrow().owner.clone()
+    }
+
+    fn keeps(&self, key: &str) -> bool {
+        self.state.borrow().kept.contains(key)
+    }
+
+    fn cipher(&self) -> Result<Cipher, SeedError> {
+        self.state
+            .borrow()
+            .cipher
+            .clone()
+            .ok_or(SeedError::NotSeeding)
+    }
+
+    async fn connect(&self, peer_id: PeerID) -> Result<DataConnection, SeedError> {
+        let (connection_tx, connection_rx) = oneshot::channel();
+        self.connect_tx
+            .send((peer_id, connection_tx))
+            .map_err(|_| SeedError::Stopped)?;
+
+        connection_rx.await.map_err(|_| SeedError::Stopped)
+    }
+}
+
+impl Drop for SeedingState {
+    fn drop(&mut self) {
+        let kept = std::mem::take(&mut self.kept);
+        if kept.is_empty() {
+            return;
+        }
+
+        let owner = std::mem::take(&mut self.owner);
+        spawn_local(async move {
+            if let Err(error) = remove_kept(&owner, &kept).await {
+                error!("Error removing chunks kept to pass on: {error}");
+            }
+        });
+    }
+}
+
+async fn remove_kept(owner: &str, kept: &HashSet<String>) -> Result<(), IdbError> {
+    let store = PartialStore::open().await?;
+    for key in kept {
+        // Files that weren't saved stay, so they can be resumed, and files another page has since
+        // saved are left to that page
+        if store
+            .get(key)
+            .await?
+            .is_some_and(|transfer| transfer.seeding && transfer.owner == owner)
+        {
+            store.remove(key).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Keeps confirming the page is passing on its kept files, until seeding stops
+async fn heartbeat(state: Weak<RefCell<SeedingState>>) {
+    loop {
+        sleep(SEED_HEARTBEAT_INTERVAL).await;
+        let Some(seeding) = state.upgrade() else {
+            return;
+        };
+        let owner = seeding.borrow().owner.clone();
+        drop(seeding);
+
+        let result = match PartialStore::open().await {
+            Ok(store) => store.refresh_seeding(&owner).await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            error!("Error confirming chunks kept to pass on: {error}");
+        }
+    }
+}
+
+/// Removes the files a closed page kept to pass on, which it had no chance to remove itself. Files
+/// whose page still confirms it's seeding, such as one in another tab, are left alone
+pub(crate) async fn remove_stale_seeds() {
+    let result = match PartialStore::open().await {
+        Ok(store) => {
+            store
+                .remove_stale_seeding(Date::now() - SEED_STALE_AFTER)
+                .await
+        }
+        Err(error) => Err(error),
+    };
+    if let Err(error) = result {
+        error!("Error removing chunks kept to pass on: {error}");
+    }
+}
+
+/// Opens seed connections for this page, and answers other receivers' seed requests. It never
+/// returns, so it can run alongside the session
+pub(crate) async fn serve_seeds(
+    client: &mut Client,
+    seeding: Option<(Seeding, SeedConnects)>,
+) -> Infallible {
+    let Some((seeding, SeedConnects(mut connect_rx))) = seeding else {
+        return pending().await;
+    };
+
+    loop {
+        select! {
+            Some((peer_id, connection_tx)) = connect_rx.recv() => {
+                let _ = connection_tx.send(client.connect(&peer_id, SEED_LABEL));
+            }
+            connection = client.receive_connection() => match connection {
+                Ok(connection) if connection.label() == SEED_LABEL => {
+                    let seeding = seeding.clone();
+                    spawn_local(async move {
+                        if let Err(error) = pass_on(connection, &seeding).await {
+                            warn!("Error passing on chunks: {error}");
+                        }
+                    });
+                }
+                Ok(connection) => warn!(
+                    "Turned away {} connection from {}",
+                    connection.label(),
+                    connection.peer_id()
+                ),
+                Err(error) => {
+                    // Receiving from the sender carries on, just without passing chunks on
+                    warn!("Stopped passing on chunks: {error}");
+                    return pending().await;
+                }
+            },
+        }
+    }
+}
+
+/// Sends another receiver the chunks it asks for, straight from the partial store
+async fn pass_on(mut connection: DataConnection, seeding: &Seeding) -> Result<(), SeedError> {
+    timeout(CONNECT_TIMEOUT, connection.wait_for_open())
+        .await
+        .map_err(|_| SeedError::OpenTimedOut)?
+        .map_err(SeedError::OpenError)?;
+
+    let cipher = seeding.cipher()?;
+    let mut link = Link::new(connection, "receiver");
+    let Message::SeedRequest(request) = receive_message(&mut link, &cipher)
+        .await
+        .map_err(SeedError::ReceiveError)?
+    else {
+        return Err(SeedError::UnexpectedMessage("seed request"));
+    };
+    if !seeding.keeps(&request.key) {
+        return Err(SeedError::UnknownFile);
+    }
+
+    let store = PartialStore::open()
+        .await
+        .map_err(SeedError::StorageError)?;
+    let transfer = store
+        .get(&request.key)
+        .await
+        .map_err(SeedError::StorageError)?;
+    if !transfer.is_some_and(|transfer| transfer.received_chunks >= request.to_chunk) {
+        return Err(SeedError::MissingChunks);
+    }
+
+    for index in request.from_chunk..request.to_chunk {
+        let chunk = store
+            .load_chunk(&request.key, index)
+            .await
+            .map_err(SeedError::StorageError)?;
+        let data = read_slice(&chunk, 0, chunk.size() as u64)
+            .await
+            .map_err(|error| {
+                jserror!("Error reading saved chunk: {}", error);
+                SeedError::ReadChunkError(index)
+            })?;
+        let proof = store
+            .load_proof(&request.key, index)
+            .await
+            .map_err(SeedError::StorageError)?
+            .ok_or(SeedError::MissingProof(index))?;
+        let len = data.len() as u64;
+        let chunk = Chunk {
+            file: request.file,
+            index,
+            codec: Codec::None,
+            data,
+            proof,
+        };
+        send_chunk(&mut link, &cipher, chunk)
+            .await
+            .map_err(SeedError::SendChunkError)?;
+        seeding.passed_on.try_update(|passed_on| *passed_on += len);
+    }
+    info!(
+        "Passed on chunks {}..{} of {}",
+        request.from_chunk, request.to_chunk, request.key
+    );
+
+    // Closing straight away could lose chunks still in the send buffer
+    let _ = timeout(CONNECT_TIMEOUT, link.control().wait_for_close()).await;
+
+    Ok(())
+}
+
+/// Fetches the chunks the sender pointed at from the seeder, saving each one whose proof leads to
+/// the file's Merkle root. Whatever was saved before an error is kept
+pub(crate) async fn fetch_from_seeder(
+    seeding: &Seeding,
+    seeder: &Seeder,
+    header: &FileHeader,
+    store: &PartialStore,
+    transfer: &mut PartialTransfer,
+    progress: Progress,
+) -> Result<(), SeedError> {
+    let full_id = seeder.peer_id.as_deref().unwrap_or_default();
+    let peer_id =
+        PeerID::from_full(full_id).ok_or_else(|| SeedError::InvalidPeerId(full_id.to_string()))?;
+
+    let mut connection = seeding.connect(peer_id).await?;
+    timeout(CONNECT_TIMEOUT, connection.wait_for_open())
+        .await
+        .map_err(|_| SeedError::OpenTimedOut)?
+        .map_err(SeedError::OpenError)?;
+
+    let cipher = seeding.cipher()?;
+    let mut link = Link::new(connection, "seeder");
+    send_message(
+        &link,
+        &cipher,
+        &Message::SeedRequest(SeedRequest {
+            key: transfer.key.clone(),
+            file: seeder.file,
+            from_chunk: seeder.from_chunk,
+            to_chunk: seeder.to_chunk,
+        }),
+    );
+
+    for index in seeder.from_chunk..seeder.to_chunk {
+        let Message::Chunk(chunk) = receive_message(&mut link, &cipher)
+            .await
+            .map_err(SeedError::ReceiveError)?
+        else {
+            return Err(SeedError::UnexpectedMessage("chunk"));
+        };
+        if chunk.file != seeder.file || chunk.index != index {
+            return Err(SeedError::OutOfOrderChunk {
+                file: chunk.file,
+                received: chunk.index,
+                expected: index,
+            });
+        }
+        let verified = merkle::verify(
+            &header.merkle_root,
+            header.chunk_count,
+            index,
+            &chunk.data,
+            &chunk.proof,
+        );
+        if !verified {
+            return Err(SeedError::CorruptChunk(index));
+        }
+
+        store
+            .put_chunk(transfer, index, &chunk.data, &chunk.proof)
+            .await
+            .map_err(SeedError::StorageError)?;
+        progress.advance(chunk.data.len() as u64);
+    }
+
+    Ok(())
+}
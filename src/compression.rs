@@ -0,0 +1,106 @@
+use std::io::Read;
+
+use ruzstd::{decoding::StreamingDecoder, encoding::CompressionLevel};
+use serde::{Deserialize, Serialize};
+
+/// Codecs this build can compress and decompress with, most preferred first
+pub const SUPPORTED_CODECS: [Codec; 2] = [Codec::Zstd, Codec::Deflate];
+
+const DEFLATE_LEVEL: u8 = 6;
+
+/// MIME types whose contents are already compressed, so compressing them again only costs time
+const COMPRESSED_MIME_PREFIXES: [&str; 3] = ["image/", "video/", "audio/"];
+const COMPRESSED_MIME_TYPES: [&str; 14] = [
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/vnd.rar",
+    "application/zstd",
+    "application/pdf",
+    "application/epub+zip",
+    "application/java-archive",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    None,
+    Deflate,
+    Zstd,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Couldn't decompress {0:?} data")]
+pub struct DecompressError(Codec);
+
+/// Picks the codec to use from those the sender offers
+pub fn negotiate(offered: &[Codec]) -> Codec {
+    SUPPORTED_CODECS
+        .into_iter()
+        .find(|codec| offered.contains(codec))
+        .unwrap_or(Codec::None)
+}
+
+/// Whether files of this MIME type are worth compressing
+pub fn is_compressible(mime_type: &str) -> bool {
+    let mime_type = mime_type.to_ascii_lowercase();
+
+    !COMPRESSED_MIME_PREFIXES
+        .iter()
+        .any(|prefix| mime_type.starts_with(prefix))
+        && !COMPRESSED_MIME_TYPES.contains(&mime_type.as_str())
+}
+
+/// Compresses a chunk. Returns the codec actually used, which is `Codec::None` when compressing
+/// didn't make the chunk any smaller
+pub fn compress(codec: Codec, data: &[u8]) -> (Codec, Vec<u8>) {
+    let compressed = match codec {
+        Codec::None => return (Codec::None, data.to_vec()),
+        Codec::Deflate => miniz_oxide::deflate::compress_to_vec(data, DEFLATE_LEVEL),
+        Codec::Zstd => ruzstd::encoding::compress_to_vec(data, CompressionLevel::Fastest),
+    };
+
+    if compressed.len() < data.len() {
+        (codec, compressed)
+    } else {
+        (Codec::None, data.to_vec())
+    }
+}
+
+/// Decompresses a chunk. Output beyond `max_len` is an error, so a malicious peer can't make the
+/// receiver inflate a small chunk into something huge
+pub fn decompress(codec: Codec, data: &[u8], max_len: usize) -> Result<Vec<u8>, DecompressError> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Deflate => miniz_oxide::inflate::decompress_to_vec_with_limit(data, max_len)
+            .map_err(|_| DecompressError(codec)),
+        Codec::Zstd => {
+            let mut source = data;
+            let decoder = StreamingDecoder::new(&mut source).map_err(|_| DecompressError(codec))?;
+            let mut decompressed = Vec::new();
+            decoder
+                .take(max_len as u64 + 1)
+                .read_to_end(&mut decompressed)
+                .map_err(|_| DecompressError(codec))?;
+            if decompressed.len() > max_len {
+                return Err(DecompressError(codec));
+            }
+
+            Ok(decompressed)
+        }
+    }
+}
+
+/// Uncompressed size over compressed size, for display
+pub fn format_ratio(uncompressed: u64, compressed: u64) -> String {
+    if compressed == 0 {
+        return "1.0x".to_string();
+    }
+
+    format!("{:.1}x", uncompressed as f64 / compressed as f64)
+}
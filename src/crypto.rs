@@ -0,0 +1,144 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+use crate::peerjs::peerid::PeerID;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+/// Random key generated by the sender. It only ever travels in the link fragment, which browsers
+/// don't send to servers, so the signalling server can't read or tamper with the transfer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShareKey([u8; KEY_SIZE]);
+
+/// Encrypts and authenticates messages with a share's key
+#[derive(Clone)]
+pub struct Cipher {
+    key: [u8; KEY_SIZE],
+    cipher: ChaCha20Poly1305,
+}
+
+/// One side of a SPAKE2 exchange, which turns a password both peers typed into a shared key
+/// without sending the password, or anything that could be used to guess it offline
+pub struct PasswordExchange(Spake2<Ed25519Group>);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Couldn't decrypt message. Is the link complete?")]
+pub struct DecryptError;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Peer sent a malformed password exchange message")]
+pub struct PasswordExchangeError;
+
+impl ShareKey {
+    pub fn generate() -> ShareKey {
+        ShareKey(ChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    pub fn decode(encoded: &str) -> Option<ShareKey> {
+        let bytes = URL_SAFE_NO_PAD.decode(encoded).ok()?;
+
+        Some(ShareKey(bytes.try_into().ok()?))
+    }
+
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.0)
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        Cipher::new(self.0)
+    }
+}
+
+impl Cipher {
+    fn new(key: [u8; KEY_SIZE]) -> Cipher {
+        Cipher {
+            key,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+
+    /// Cipher for links to password-protected shares that leave the key out. Its key is public,
+    /// so it only carries the handshake and the password exchange, whose key is mixed in after
+    pub fn keyless() -> Cipher {
+        Cipher::new([0; KEY_SIZE])
+    }
+
+    /// Mixes in the key from a password exchange. Peers that typed different passwords end up
+    /// with ciphers that can't read each other's messages
+    pub fn with_password_key(&self, password_key: &[u8]) -> Cipher {
+        let key = Sha256::new()
+            .chain_update(b"file-transfer password\n")
+            .chain_update(self.key)
+            .chain_update(password_key)
+            .finalize();
+
+        Cipher::new(key.into())
+    }
+
+    /// Encrypts with a random nonce, which is prepended to the result
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, plaintext).unwrap();
+
+        [nonce.as_slice(), ciphertext.as_slice()].concat()
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        if data.len() < NONCE_SIZE {
+            return Err(DecryptError);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| DecryptError)
+    }
+}
+
+impl PasswordExchange {
+    /// Returns the exchange and the message to send the peer
+    pub fn start(password: &str) -> (PasswordExchange, Vec<u8>) {
+        let (state, message) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(password.as_bytes()),
+            &Identity::new(b"file-transfer password"),
+        );
+
+        (PasswordExchange(state), message)
+    }
+
+    /// Finishes with the peer's message, giving the key to pass to `Cipher::with_password_key`.
+    /// A wrong password still gives a key, just not the same one the peer has
+    pub fn finish(self, peer_message: &[u8]) -> Result<Vec<u8>, PasswordExchangeError> {
+        self.0
+            .finish(peer_message)
+            .map_err(|_| PasswordExchangeError)
+    }
+}
+
+/// The part of a sharing link after the `#`, of the form `ABCD.<key>`. Links to
+/// password-protected shares can leave the key out, as just `ABCD`, in which case the password
+/// exchange alone keeps the signalling server out
+pub fn share_code(peer_id: &PeerID, key: Option<&ShareKey>) -> String {
+    match key {
+        Some(key) => format!("{}.{}", peer_id.base(), key.encode()),
+        None => peer_id.base().to_string(),
+    }
+}
+
+pub fn parse_share_code(code: &str) -> Option<(PeerID, Option<ShareKey>)> {
+    let Some((peer_id, key)) = code.split_once('.') else {
+        return Some((PeerID::new_short_id(code.to_string())?, None));
+    };
+
+    Some((
+        PeerID::new_short_id(peer_id.to_string())?,
+        Some(ShareKey::decode(key)?),
+    ))
+}
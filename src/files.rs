@@ -0,0 +1,107 @@
+use js_sys::{Array, Promise, Reflect, Uint8Array};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    Blob, DataTransfer, File, FileList, FileSystemDirectoryEntry, FileSystemEntry,
+    FileSystemFileEntry,
+};
+
+/// A file picked to be sent, along with its path relative to the folder it was picked from
+#[derive(Clone)]
+pub struct SelectedFile {
+    pub file: File,
+    /// `/` separated. Just the file's name if it wasn't picked as part of a folder
+    pub path: String,
+}
+
+impl SelectedFile {
+    fn new(file: File, path: String) -> SelectedFile {
+        let path = if path.is_empty() { file.name() } else { path };
+        SelectedFile { file, path }
+    }
+}
+
+/// Returns every file in the list. Files picked through a `webkitdirectory` input keep their
+/// path within the picked folder
+pub fn from_file_list(file_list: &FileList) -> Vec<SelectedFile> {
+    (0..file_list.length())
+        .filter_map(|i| file_list.item(i))
+        .map(|file| {
+            let path = Reflect::get(&file, &"webkitRelativePath".into())
+                .ok()
+                .and_then(|path| path.as_string())
+                .unwrap_or_default();
+            SelectedFile::new(file, path)
+        })
+        .collect()
+}
+
+/// Returns the entries dropped onto the page. Must be called while handling the drop event, as
+/// the browser clears the data transfer afterwards
+pub fn dropped_entries(data_transfer: &DataTransfer) -> Vec<FileSystemEntry> {
+    let items = data_transfer.items();
+    (0..items.length())
+        .filter_map(|i| items.get(i))
+        .filter(|item| item.kind() == "file")
+        .filter_map(|item| item.webkit_get_as_entry().ok().flatten())
+        .collect()
+}
+
+/// Reads every file out of the dropped entries, walking into any folders
+pub async fn from_entries(entries: Vec<FileSystemEntry>) -> Result<Vec<SelectedFile>, JsValue> {
+    let mut files = Vec::new();
+    let mut pending = entries;
+
+    while let Some(entry) = pending.pop() {
+        if entry.is_directory() {
+            let directory = entry.unchecked_into::<FileSystemDirectoryEntry>();
+            pending.extend(read_directory(&directory).await?);
+            continue;
+        }
+
+        let path = entry.full_path().trim_start_matches('/').to_string();
+        let file_entry = entry.unchecked_into::<FileSystemFileEntry>();
+        let file = JsFuture::from(Promise::new(&mut |resolve, reject| {
+            file_entry.file_with_callback_and_callback(&resolve, &reject)
+        }))
+        .await?;
+
+        files.push(SelectedFile::new(file.unchecked_into(), path));
+    }
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(files)
+}
+
+async fn read_directory(
+    directory: &FileSystemDirectoryEntry,
+) -> Result<Vec<FileSystemEntry>, JsValue> {
+    let reader = directory.create_reader();
+
+    // readEntries only returns a batch at a time, and an empty batch once it's done
+    let mut entries = Vec::new();
+    loop {
+        let batch = JsFuture::from(Promise::new(&mut |resolve, reject| {
+            let _ = reader.read_entries_with_callback_and_callback(&resolve, &reject);
+        }))
+        .await?
+        .unchecked_into::<Array>();
+
+        if batch.length() == 0 {
+            break;
+        }
+
+        entries.extend(batch.iter().map(|entry| entry.unchecked_into()));
+    }
+
+    Ok(entries)
+}
+
+/// Reads the bytes of the blob between `start` and `end`
+pub async fn read_slice(blob: &Blob, start: u64, end: u64) -> Result<Vec<u8>, JsValue> {
+    let slice = blob.slice_with_f64_and_f64(start as f64, end as f64)?;
+    let buffer = JsFuture::from(slice.array_buffer()).await?;
+
+    Ok(Uint8Array::new(&buffer).to_vec())
+}
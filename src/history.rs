@@ -0,0 +1,146 @@
+use gloo_utils::format::JsValueSerdeExt;
+use js_sys::Array;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use wasm_bindgen::JsValue;
+use web_sys::{IdbDatabase, IdbTransactionMode};
+
+use crate::{
+    idb::{self, Database, IdbError},
+    protocol::FileHeader,
+    utils::to_hex,
+};
+
+const DATABASE_NAME: &str = "transfer-history";
+const DATABASE_VERSION: u32 = 1;
+const ENTRIES_STORE: &str = "entries";
+
+/// Every file sent or received, kept in IndexedDB after the tab closes
+pub struct HistoryStore {
+    database: Database,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: Uuid,
+    pub path: String,
+    pub size: u64,
+    /// SHA-256 of the whole file, in hex
+    pub hash: String,
+    pub direction: HistoryDirection,
+    pub peer_id: String,
+    /// Milliseconds since the epoch
+    pub started: f64,
+    pub ended: f64,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum HistoryDirection {
+    Sent,
+    Received,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Outcome {
+    Completed,
+    Declined,
+    Failed,
+}
+
+impl HistoryStore {
+    pub async fn open() -> Result<HistoryStore, IdbError> {
+        let database = Database::open(DATABASE_NAME, DATABASE_VERSION, upgrade).await?;
+
+        Ok(HistoryStore { database })
+    }
+
+    /// Returns every entry, oldest first
+    pub async fn all(&self) -> Result<Vec<HistoryEntry>, IdbError> {
+        let transaction = self
+            .database
+            .transaction(&[ENTRIES_STORE], IdbTransactionMode::Readonly)?;
+        let values = idb::request(transaction.store(ENTRIES_STORE)?.get_all()).await?;
+
+        let mut entries = Array::from(&values)
+            .iter()
+            .filter_map(|value| value.into_serde::<HistoryEntry>().ok())
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.started.total_cmp(&b.started));
+
+        Ok(entries)
+    }
+
+    pub async fn add(&self, entries: &[HistoryEntry]) -> Result<(), IdbError> {
+        let transaction = self
+            .database
+            .transaction(&[ENTRIES_STORE], IdbTransactionMode::Readwrite)?;
+        let store = transaction.store(ENTRIES_STORE)?;
+        for entry in entries {
+            store
+                .put_with_key(
+                    &JsValue::from_serde(entry).unwrap(),
+                    &entry.id.to_string().into(),
+                )
+                .map_err(IdbError::RequestFailed)?;
+        }
+
+        transaction.commit().await
+    }
+
+    pub async fn remove(&self, id: Uuid) -> Result<(), IdbError> {
+        let transaction = self
+            .database
+            .transaction(&[ENTRIES_STORE], IdbTransactionMode::Readwrite)?;
+        transaction
+            .store(ENTRIES_STORE)?
+            .delete(&id.to_string().into())
+            .map_err(IdbError::RequestFailed)?;
+
+        transaction.commit().await
+    }
+}
+
+impl HistoryEntry {
+    pub fn new(
+        header: &FileHeader,
+        direction: HistoryDirection,
+        peer_id: &str,
+        started: f64,
+        ended: f64,
+        result: Result<Outcome, String>,
+    ) -> HistoryEntry {
+        let (outcome, error) = match result {
+            Ok(outcome) => (outcome, None),
+            Err(error) => (Outcome::Failed, Some(error)),
+        };
+
+        HistoryEntry {
+            id: Uuid::new_v4(),
+            path: header.path.clone(),
+            size: header.size,
+            hash: to_hex(&header.hash),
+            direction,
+            peer_id: peer_id.to_string(),
+            started,
+            ended,
+            outcome,
+            error,
+        }
+    }
+
+    /// Whether the search text appears in the path, peer ID or hash, ignoring case
+    pub fn matches(&self, search: &str) -> bool {
+        let search = search.to_lowercase();
+        [&self.path, &self.peer_id, &self.hash]
+            .iter()
+            .any(|field| field.to_lowercase().contains(&search))
+    }
+}
+
+fn upgrade(database: &IdbDatabase, old_version: u32) {
+    if old_version < 1 {
+        database.create_object_store(ENTRIES_STORE).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use js_sys::Array;
+use tokio::sync::mpsc;
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{
+    IdbDatabase, IdbObjectStore, IdbRequest, IdbTransaction, IdbTransactionMode,
+    IdbVersionChangeEvent,
+};
+
+pub struct Database {
+    internal_database: IdbDatabase,
+}
+
+pub struct Transaction {
+    internal_transaction: IdbTransaction,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdbError {
+    #[error("IndexedDB not found. Browser up-to-date?")]
+    Unavailable,
+    #[error("IndexedDB request failed: {0:?}")]
+    RequestFailed(JsValue),
+    #[error("IndexedDB transaction failed: {0:?}")]
+    TransactionFailed(JsValue),
+    #[error("Request callback closed unexpectedly")]
+    CallbackClosed,
+}
+
+impl Database {
+    /// Opens the named database, calling `upgrade` with the old version if it needs creating or
+    /// migrating
+    pub async fn open(
+        name: &str,
+        version: u32,
+        upgrade: fn(&IdbDatabase, u32),
+    ) -> Result<Database, IdbError> {
+        let factory = match web_sys::window().unwrap().indexed_db() {
+            Ok(Some(v)) => v,
+            Ok(None) => return Err(IdbError::Unavailable),
+            Err(error) => return Err(IdbError::RequestFailed(error)),
+        };
+
+        let request = factory
+            .open_with_u32(name, version)
+            .map_err(IdbError::RequestFailed)?;
+
+        let request_ = request.clone();
+        let on_upgrade_needed =
+            Closure::<dyn Fn(IdbVersionChangeEvent)>::new(move |event: IdbVersionChangeEvent| {
+                let database = request_.result().unwrap().unchecked_into::<IdbDatabase>();
+                upgrade(&database, event.old_version() as u32);
+            });
+        request.set_onupgradeneeded(Some(on_upgrade_needed.as_ref().unchecked_ref()));
+
+        let result = wait_for_request(&request).await;
+        request.set_onupgradeneeded(None);
+
+        Ok(Database {
+            internal_database: result?.unchecked_into(),
+        })
+    }
+
+    pub fn transaction(
+        &self,
+        stores: &[&str],
+        mode: IdbTransactionMode,
+    ) -> Result<Transaction, IdbError> {
+        let store_names = stores
+            .iter()
+            .map(|v| JsValue::from_str(v))
+            .collect::<Array>();
+
+        let internal_transaction = self
+            .internal_database
+            .transaction_with_str_sequence_and_mode(&store_names, mode)
+            .map_err(IdbError::TransactionFailed)?;
+
+        Ok(Transaction {
+            internal_transaction,
+        })
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        self.internal_database.close();
+    }
+}
+
+impl Transaction {
+    pub fn store(&self, name: &str) -> Result<IdbObjectStore, IdbError> {
+        self.internal_transaction
+            .object_store(name)
+            .map_err(IdbError::TransactionFailed)
+    }
+
+    /// Waits for every request made in the transaction to be committed
+    pub async fn commit(self) -> Result<(), IdbError> {
+        let transaction = &self.internal_transaction;
+
+        let (result_tx, mut result_rx) = mpsc::channel(1);
+        let complete_tx = result_tx.clone();
+        let on_complete = Closure::<dyn Fn()>::new(move || {
+            let _ = complete_tx.try_send(true);
+        });
+        let on_error = Closure::<dyn Fn()>::new(move || {
+            let _ = result_tx.try_send(false);
+        });
+        transaction.set_oncomplete(Some(on_complete.as_ref().unchecked_ref()));
+        transaction.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+        transaction.set_onabort(Some(on_error.as_ref().unchecked_ref()));
+
+        let _ = transaction.commit();
+        let result = result_rx.recv().await;
+
+        transaction.set_oncomplete(None);
+        transaction.set_onerror(None);
+        transaction.set_onabort(None);
+
+        match result {
+            Some(true) => Ok(()),
+            Some(false) => Err(IdbError::TransactionFailed(
+                transaction.error().map(JsValue::from).unwrap_or_default(),
+            )),
+            None => Err(IdbError::CallbackClosed),
+        }
+    }
+}
+
+/// Waits for a request to finish, returning its result
+pub async fn request(request: Result<IdbRequest, JsValue>) -> Result<JsValue, IdbError> {
+    let request = request.map_err(IdbError::RequestFailed)?;
+    wait_for_request(&request).await
+}
+
+async fn wait_for_request(request: &IdbRequest) -> Result<JsValue, IdbError> {
+    let (result_tx, mut result_rx) = mpsc::channel(1);
+    let success_tx = result_tx.clone();
+    let on_success = Closure::<dyn Fn()>::new(move || {
+        let _ = success_tx.try_send(true);
+    });
+    let on_error = Closure::<dyn Fn()>::new(move || {
+        let _ = result_tx.try_send(false);
+    });
+    request.set_onsuccess(Some(on_success.as_ref().unchecked_ref()));
+    request.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+
+    let result = result_rx.recv().await;
+
+    request.set_onsuccess(None);
+    request.set_onerror(None);
+
+    match result {
+        Some(true) => request.result().map_err(IdbError::RequestFailed),
+        Some(false) => Err(IdbError::RequestFailed(
+            request
+                .error()
+                .ok()
+                .flatten()
+                .map(JsValue::from)
+                .unwrap_or_default(),
+        )),
+        None => Err(IdbError::CallbackClosed),
+    }
+}
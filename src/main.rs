@@ -1,8 +1,21 @@
 use components::app::App;
 
 mod components;
+mod compression;
+mod crypto;
+mod files;
+mod history;
+mod idb;
+mod merkle;
+mod partial;
 mod peerjs;
+mod protocol;
+mod queue;
+mod save;
+mod sink;
 mod utils;
+mod verification;
+mod zip;
 
 fn main() {
     console_error_panic_hook::set_once();
@@ -0,0 +1,238 @@
+use sha2::{Digest, Sha256};
+use wasm_bindgen::JsValue;
+use web_sys::Blob;
+
+use crate::{files::read_slice, protocol::CHUNK_SIZE};
+
+/// Prefixes keeping leaf hashes and node hashes apart, so a node can't pass as a chunk
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Tree of SHA-256 hashes over the chunks of a file. A node without a sibling is moved up a level
+/// unchanged
+pub struct MerkleTree {
+    /// Leaf hashes first, ending with the level holding just the root
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<[u8; 32]>) -> MerkleTree {
+        let leaves = if leaves.is_empty() {
+            vec![leaf_hash(&[])]
+        } else {
+            leaves
+        };
+
+        let mut levels = vec![leaves];
+        while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+            let next_level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [only] => *only,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next_level);
+        }
+
+        MerkleTree { levels }
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.levels.last().unwrap()[0]
+    }
+
+    /// Sibling hashes on the path from the chunk at `index` up to the root, lowest first
+    pub fn proof(&self, index: u64) -> Vec<[u8; 32]> {
+        let mut index = index as usize;
+        let mut proof = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+
+        proof
+    }
+}
+
+/// Checks that `data` is the chunk at `index` of a file with the given root and chunk count
+pub fn verify(
+    root: &[u8; 32],
+    chunk_count: u64,
+    index: u64,
+    data: &[u8],
+    proof: &[[u8; 32]],
+) -> bool {
+    if index >= chunk_count {
+        return false;
+    }
+
+    let mut hash = leaf_hash(data);
+    let mut proof = proof.iter();
+    let mut index = index;
+    let mut width = chunk_count;
+    while width > 1 {
+        if index ^ 1 < width {
+            let Some(sibling) = proof.next() else {
+                return false;
+            };
+            hash = if index & 1 == 0 {
+                node_hash(&hash, sibling)
+            } else {
+                node_hash(sibling, &hash)
+            };
+        }
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+
+    proof.next().is_none() && hash == *root
+}
+
+/// Reads the blob chunk by chunk, returning its SHA-256 and the Merkle tree of its chunks
+pub async fn hash_blob(blob: &Blob) -> Result<([u8; 32], MerkleTree), JsValue> {
+    let size = blob.size() as u64;
+
+    let mut hasher = Sha256::new();
+    let mut leaves = Vec::new();
+    let mut start = 0;
+    while start < size {
+        let end = (start + CHUNK_SIZE).min(size);
+        let data = read_slice(blob, start, end).await?;
+        hasher.update(&data);
+        leaves.push(leaf_hash(&data));
+        start = end;
+    }
+
+    Ok((hasher.finalize().into(), MerkleTree::new(leaves)))
+}
+
+fn leaf_hash(data: &[u8]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([LEAF_PREFIX])
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([NODE_PREFIX])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(count: u64) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i as u8; 10 + i as usize]).collect()
+    }
+
+    fn tree(chunks: &[Vec<u8>]) -> MerkleTree {
+        MerkleTree::new(chunks.iter().map(|chunk| leaf_hash(chunk)).collect())
+    }
+
+    #[test]
+    fn every_chunk_verifies() {
+        for count in [1, 2, 3, 5, 9, 17, 33] {
+            let chunks = chunks(count);
+            let tree = tree(&chunks);
+            for (index, chunk) in (0..).zip(&chunks) {
+                let proof = tree.proof(index);
+                assert!(
+                    verify(&tree.root(), count, index, chunk, &proof),
+                    "chunk {index} of {count}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn empty_file_has_nothing_to_verify() {
+        let tree = tree(&[]);
+
+        assert_eq!(tree.root(), leaf_hash(&[]));
+        assert!(!verify(&tree.root(), 0, 0, &[], &tree.proof(0)));
+    }
+
+    #[test]
+    fn single_chunk_is_its_own_root() {
+        let chunks = chunks(1);
+        let tree = tree(&chunks);
+
+        assert_eq!(tree.root(), leaf_hash(&chunks[0]));
+        assert!(tree.proof(0).is_empty());
+    }
+
+    #[test]
+    fn tampered_leaf_fails() {
+        for count in [1, 2, 3, 5, 17] {
+            let chunks = chunks(count);
+            let tree = tree(&chunks);
+            for (index, chunk) in (0..).zip(&chunks) {
+                let mut tampered = chunk.clone();
+                tampered[0] ^= 1;
+                assert!(!verify(
+                    &tree.root(),
+                    count,
+                    index,
+                    &tampered,
+                    &tree.proof(index)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_proof_fails() {
+        for count in [2, 3, 5, 17] {
+            let chunks = chunks(count);
+            let tree = tree(&chunks);
+            for (index, chunk) in (0..).zip(&chunks) {
+                let proof = tree.proof(index);
+                for sibling in 0..proof.len() {
+                    let mut tampered = proof.clone();
+                    tampered[sibling][0] ^= 1;
+                    assert!(!verify(&tree.root(), count, index, chunk, &tampered));
+                }
+
+                let mut extended = proof.clone();
+                extended.push([0; 32]);
+                assert!(!verify(&tree.root(), count, index, chunk, &extended));
+                assert!(!verify(
+                    &tree.root(),
+                    count,
+                    index,
+                    chunk,
+                    &proof[..proof.len() - 1]
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_fails_at_another_index() {
+        let chunks = chunks(5);
+        let tree = tree(&chunks);
+
+        assert!(!verify(&tree.root(), 5, 1, &chunks[0], &tree.proof(0)));
+        assert!(!verify(&tree.root(), 5, 5, &chunks[4], &tree.proof(4)));
+    }
+
+    #[test]
+    fn node_cannot_pass_as_chunk() {
+        let chunks = chunks(4);
+        let tree = tree(&chunks);
+        let node = [leaf_hash(&chunks[0]), leaf_hash(&chunks[1])].concat();
+
+        // The node over the first two chunks, posing as a chunk of a file half as long
+        assert!(!verify(&tree.root(), 2, 0, &node, &tree.proof(0)[1..]));
+    }
+}
@@ -0,0 +1,281 @@
+use gloo_utils::format::JsValueSerdeExt;
+use js_sys::{Array, Date, Uint8Array};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+use web_sys::{Blob, IdbDatabase, IdbKeyRange, IdbTransactionMode};
+
+use crate::{
+    idb::{self, Database, IdbError},
+    protocol::FileHeader,
+    utils::to_hex,
+};
+
+const DATABASE_NAME: &str = "partial-transfers";
+const DATABASE_VERSION: u32 = 2;
+const TRANSFERS_STORE: &str = "transfers";
+const CHUNKS_STORE: &str = "chunks";
+/// Merkle proof of each chunk, kept so the chunk can be passed on to other receivers
+const PROOFS_STORE: &str = "proofs";
+
+/// Chunks of unfinished downloads, kept in IndexedDB so they survive dropped connections and
+/// page reloads
+pub struct PartialStore {
+    database: Database,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PartialTransfer {
+    pub key: String,
+    pub path: String,
+    pub size: u64,
+    pub chunk_count: u64,
+    /// Number of chunks, counted from the start of the file, that have been saved
+    pub received_chunks: u64,
+    /// Set once the file is saved, when its chunks are only kept to pass on to other receivers.
+    /// It's not offered for resuming
+    #[serde(default)]
+    pub seeding: bool,
+    /// The page passing the file on
+    #[serde(default)]
+    pub owner: String,
+    /// When the owner last confirmed it's still passing the file on, in milliseconds since the
+    /// epoch. Once it stops, the page was closed and the file is removed by the next one to start
+    #[serde(default)]
+    pub heartbeat: f64,
+}
+
+impl PartialStore {
+    pub async fn open() -> Result<PartialStore, IdbError> {
+        let database = Database::open(DATABASE_NAME, DATABASE_VERSION, upgrade).await?;
+
+        Ok(PartialStore { database })
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<PartialTransfer>, IdbError> {
+        let transaction = self
+            .database
+            .transaction(&[TRANSFERS_STORE], IdbTransactionMode::Readonly)?;
+        let value = idb::request(transaction.store(TRANSFERS_STORE)?.get(&key.into())).await?;
+
+        if value.is_undefined() {
+            return Ok(None);
+        }
+
+        Ok(value.into_serde().ok())
+    }
+
+    /// Saves the chunk at `index` with its Merkle proof, and marks every chunk up to and including
+    /// it as received
+    pub async fn put_chunk(
+        &self,
+        transfer: &mut PartialTransfer,
+        index: u64,
+        data: &[u8],
+        proof: &[[u8; 32]],
+    ) -> Result<(), IdbError> {
+        let parts = Array::new();
+        parts.push(&Uint8Array::from(data));
+        let blob = Blob::new_with_u8_array_sequence(&parts).map_err(IdbError::RequestFailed)?;
+
+        transfer.received_chunks = index + 1;
+
+        let transaction = self.database.transaction(
+            &[TRANSFERS_STORE, CHUNKS_STORE, PROOFS_STORE],
+            IdbTransactionMode::Readwrite,
+        )?;
+        transaction
+            .store(CHUNKS_STORE)?
+            .put_with_key(&blob, &chunk_key(&transfer.key, index))
+            .map_err(IdbError::RequestFailed)?;
+        transaction
+            .store(PROOFS_STORE)?
+            .put_with_key(
+                &Uint8Array::from(proof.concat().as_slice()),
+                &chunk_key(&transfer.key, index),
+            )
+            .map_err(IdbError::RequestFailed)?;
+        transaction
+            .store(TRANSFERS_STORE)?
+            .put_with_key(
+                &JsValue::from_serde(transfer).unwrap(),
+                &transfer.key.as_str().into(),
+            )
+            .map_err(IdbError::RequestFailed)?;
+
+        transaction.commit().await
+    }
+
+    /// Marks a saved file as kept only to pass on, by the page `owner`
+    pub async fn mark_seeding(
+        &self,
+        transfer: &mut PartialTransfer,
+        owner: &str,
+    ) -> Result<(), IdbError> {
+        transfer.seeding = true;
+        transfer.owner = owner.to_string();
+        transfer.heartbeat = Date::now();
+
+        self.put_transfers(std::slice::from_ref(transfer)).await
+    }
+
+    /// Confirms the page `owner` is still passing on the files it keeps
+    pub async fn refresh_seeding(&self, owner: &str) -> Result<(), IdbError> {
+        let now = Date::now();
+        let transfers = self
+            .seeding_transfers()
+            .await?
+            .into_iter()
+            .filter(|transfer| transfer.owner == owner)
+            .map(|transfer| PartialTransfer {
+                heartbeat: now,
+                ..transfer
+            })
+            .collect::<Vec<_>>();
+
+        self.put_transfers(&transfers).await
+    }
+
+    /// Removes the files kept only to pass on whose page last confirmed it before `stale_before`.
+    /// A page closed while seeding can't remove them itself, so this is done when the app starts
+    pub async fn remove_stale_seeding(&self, stale_before: f64) -> Result<(), IdbError> {
+        let keys = self
+            .seeding_transfers()
+            .await?
+            .into_iter()
+            .filter(|transfer| transfer.heartbeat < stale_before)
+            .map(|transfer| transfer.key)
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.remove(&key).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn seeding_transfers(&self) -> Result<Vec<PartialTransfer>, IdbError> {
+        let transaction = self
+            .database
+            .transaction(&[TRANSFERS_STORE], IdbTransactionMode::Readonly)?;
+        let values = idb::request(transaction.store(TRANSFERS_STORE)?.get_all()).await?;
+
+        Ok(Array::from(&values)
+            .iter()
+            .filter_map(|value| value.into_serde::<PartialTransfer>().ok())
+            .filter(|transfer| transfer.seeding)
+            .collect())
+    }
+
+    async fn put_transfers(&self, transfers: &[PartialTransfer]) -> Result<(), IdbError> {
+        let transaction = self
+            .database
+            .transaction(&[TRANSFERS_STORE], IdbTransactionMode::Readwrite)?;
+        let store = transaction.store(TRANSFERS_STORE)?;
+        for transfer in transfers {
+            store
+                .put_with_key(
+                    &JsValue::from_serde(transfer).unwrap(),
+                    &transfer.key.as_str().into(),
+                )
+                .map_err(IdbError::RequestFailed)?;
+        }
+
+        transaction.commit().await
+    }
+
+    /// Returns the saved chunk at `index`, to replay it when resuming
+    pub async fn load_chunk(&self, key: &str, index: u64) -> Result<Blob, IdbError> {
+        let transaction = self
+            .database
+            .transaction(&[CHUNKS_STORE], IdbTransactionMode::Readonly)?;
+        let chunk =
+            idb::request(transaction.store(CHUNKS_STORE)?.get(&chunk_key(key, index))).await?;
+
+        Ok(chunk.into())
+    }
+
+    /// Returns the Merkle proof saved with the chunk at `index`. Chunks saved before proofs were
+    /// kept have none
+    pub async fn load_proof(
+        &self,
+        key: &str,
+        index: u64,
+    ) -> Result<Option<Vec<[u8; 32]>>, IdbError> {
+        let transaction = self
+            .database
+            .transaction(&[PROOFS_STORE], IdbTransactionMode::Readonly)?;
+        let proof =
+            idb::request(transaction.store(PROOFS_STORE)?.get(&chunk_key(key, index))).await?;
+
+        if proof.is_undefined() {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            Uint8Array::new(&proof)
+                .to_vec()
+                .chunks_exact(32)
+                .map(|hash| hash.try_into().unwrap())
+                .collect(),
+        ))
+    }
+
+    pub async fn remove(&self, key: &str) -> Result<(), IdbError> {
+        let transaction = self.database.transaction(
+            &[TRANSFERS_STORE, CHUNKS_STORE, PROOFS_STORE],
+            IdbTransactionMode::Readwrite,
+        )?;
+        transaction
+            .store(CHUNKS_STORE)?
+            .delete(&chunks_range(key))
+            .map_err(IdbError::RequestFailed)?;
+        transaction
+            .store(PROOFS_STORE)?
+            .delete(&chunks_range(key))
+            .map_err(IdbError::RequestFailed)?;
+        transaction
+            .store(TRANSFERS_STORE)?
+            .delete(&key.into())
+            .map_err(IdbError::RequestFailed)?;
+
+        transaction.commit().await
+    }
+}
+
+impl PartialTransfer {
+    pub fn new(header: &FileHeader) -> PartialTransfer {
+        PartialTransfer {
+            key: transfer_key(header),
+            path: header.path.clone(),
+            size: header.size,
+            chunk_count: header.chunk_count,
+            received_chunks: 0,
+            seeding: false,
+            owner: String::new(),
+            heartbeat: 0.0,
+        }
+    }
+}
+
+/// Identifies a file by its path, size and hash, so a transfer can be resumed from any share of
+/// the same file
+pub fn transfer_key(header: &FileHeader) -> String {
+    format!("{}:{}:{}", to_hex(&header.hash), header.size, header.path)
+}
+
+fn upgrade(database: &IdbDatabase, old_version: u32) {
+    if old_version < 1 {
+        database.create_object_store(TRANSFERS_STORE).unwrap();
+        database.create_object_store(CHUNKS_STORE).unwrap();
+    }
+    if old_version < 2 {
+        database.create_object_store(PROOFS_STORE).unwrap();
+    }
+}
+
+fn chunk_key(key: &str, index: u64) -> JsValue {
+    Array::of2(&key.into(), &(index as f64).into()).into()
+}
+
+fn chunks_range(key: &str) -> IdbKeyRange {
+    IdbKeyRange::bound(&chunk_key(key, 0), &chunk_key(key, u64::MAX)).unwrap()
+}
@@ -97,11 +97,14 @@ impl Client {
         }
     }
 
-    pub fn connect(&self, peer_id: PeerID) -> DataConnection {
-        debug!("Connecting to peer '{}'", peer_id.full());
+    /// Opens a connection to the peer. The label tells the peer what the connection is for, so
+    /// several can be opened to the same peer
+    pub fn connect(&self, peer_id: &PeerID, label: &str) -> DataConnection {
+        debug!("Connecting to peer '{}' ({label})", peer_id.full());
 
         let options = Object::new();
         Reflect::set(&options, &"reliable".into(), &JsValue::from_bool(true)).unwrap();
+        Reflect::set(&options, &"label".into(), &label.into()).unwrap();
 
         let internal_connection = self.internal_peer.connect(peer_id.full(), &options);
 
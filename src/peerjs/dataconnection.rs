@@ -1,17 +1,21 @@
+use js_sys::{ArrayBuffer, Uint8Array};
 use log::debug;
 use tokio::{select, sync::mpsc};
-use wasm_bindgen::JsValue;
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
 
 use super::{
     client::{ClientError, PeerErrorHandle},
     ffi,
 };
 
+/// Sending waits while more than this many bytes are queued in the data channel
+const BUFFERED_LOW_WATER_MARK: u32 = 256 * 1024;
+
 pub struct DataConnection {
     internal_connection: ffi::DataConnection,
     data_rx: mpsc::Receiver<JsValue>,
     open_rx: mpsc::Receiver<()>,
-    close_rx: mpsc::Receiver<()>,
+    close: CloseEvent,
     error_rx: mpsc::Receiver<ffi::Error>,
     peer_error_handle: PeerErrorHandle,
 }
@@ -28,6 +32,8 @@ pub enum DataConnectionError {
     CloseCallbackClosed,
     #[error("Error callback closed unexpectedly")]
     ErrorCallbackClosed,
+    #[error("Connection closed")]
+    Closed,
     #[error("Peer error: {0}")]
     PeerError(ClientError),
     #[error("Unknown error: {0:?}")]
@@ -48,7 +54,10 @@ impl DataConnection {
             internal_connection,
             data_rx,
             open_rx,
-            close_rx,
+            close: CloseEvent {
+                close_rx,
+                closed: false,
+            },
             error_rx,
             peer_error_handle,
         }
@@ -65,19 +74,63 @@ impl DataConnection {
         }
     }
 
-    pub fn send_string(&self, value: &str) {
-        let js_value = JsValue::from_str(value);
-        self.internal_connection.send(&js_value);
-    }
-
     pub fn send(&self, value: &JsValue) {
         self.internal_connection.send(value);
     }
 
+    pub fn send_bytes(&self, value: &[u8]) {
+        let js_value = Uint8Array::from(value);
+        self.send(&js_value);
+    }
+
+    /// Bytes queued in the data channel that haven't been sent yet
+    pub fn buffered_amount(&self) -> u32 {
+        self.internal_connection
+            .data_channel()
+            .map_or(0, |channel| channel.buffered_amount())
+    }
+
+    /// Waits until the data channel's send queue is down to the low-water mark, so a fast sender
+    /// doesn't pile data up in memory faster than the connection can carry it
+    pub async fn wait_for_buffer_low(&mut self) -> Result<(), DataConnectionError> {
+        if self.buffered_amount() <= BUFFERED_LOW_WATER_MARK {
+            return Ok(());
+        }
+        let Some(channel) = self.internal_connection.data_channel() else {
+            return Ok(());
+        };
+
+        let (low_tx, mut low_rx) = mpsc::channel(1);
+        let closure = Closure::<dyn Fn()>::new(move || {
+            let _ = low_tx.try_send(());
+        });
+        channel.set_buffered_amount_low_threshold(BUFFERED_LOW_WATER_MARK);
+        channel
+            .add_event_listener_with_callback("bufferedamountlow", closure.as_ref().unchecked_ref())
+            .map_err(DataConnectionError::Unknown)?;
+
+        let result = select! {
+            _ = low_rx.recv() => Ok(()),
+            _ = self.close.recv() => Err(DataConnectionError::Closed),
+            v = recv_data_error(&mut self.error_rx) => Err(v),
+            v = self.peer_error_handle.recv() => Err(DataConnectionError::PeerError(v)),
+        };
+
+        let _ = channel.remove_event_listener_with_callback(
+            "bufferedamountlow",
+            closure.as_ref().unchecked_ref(),
+        );
+
+        result
+    }
+
     pub async fn receive<T: TryFrom<JsValue, Error = impl std::fmt::Debug>>(
         &mut self,
     ) -> Result<T, DataConnectionError> {
+        // Data that arrived before the close is still received, as the last message often says
+        // why the connection is closing
         select! {
+            biased;
             v = self.data_rx.recv() => match v {
                 Some(v) => match v.try_into() {
                     Ok(s) => Ok(s),
@@ -85,14 +138,20 @@ impl DataConnection {
                 },
                 None => Err(DataConnectionError::DataCallbackClosed),
             },
+            _ = self.close.recv() => Err(DataConnectionError::Closed),
             v = recv_data_error(&mut self.error_rx) => Err(v),
             v = self.peer_error_handle.recv() => Err(DataConnectionError::PeerError(v)),
         }
     }
 
+    pub async fn receive_bytes(&mut self) -> Result<Vec<u8>, DataConnectionError> {
+        let buffer = self.receive::<ArrayBuffer>().await?;
+        Ok(Uint8Array::new(&buffer).to_vec())
+    }
+
     pub async fn wait_for_close(&mut self) -> Result<(), DataConnectionError> {
         select! {
-            v = self.close_rx.recv() => match v {
+            v = self.close.recv() => match v {
                 Some(_) => Ok(()),
                 None => Err(DataConnectionError::CloseCallbackClosed),
             },
@@ -104,6 +163,42 @@ impl DataConnection {
     pub fn peer_id(&self) -> String {
         self.internal_connection.peer()
     }
+
+    pub fn label(&self) -> String {
+        self.internal_connection.label()
+    }
+
+    /// DTLS certificate fingerprints of the local and remote ends, read from the session
+    /// descriptions. Only available once the connection is open
+    pub fn fingerprints(&self) -> Option<(String, String)> {
+        let peer_connection = self.internal_connection.peer_connection()?;
+        let local = sdp_fingerprint(&peer_connection.local_description()?.sdp())?;
+        let remote = sdp_fingerprint(&peer_connection.remote_description()?.sdp())?;
+
+        Some((local, remote))
+    }
+}
+
+fn sdp_fingerprint(sdp: &str) -> Option<String> {
+    sdp.lines()
+        .find_map(|line| line.strip_prefix("a=fingerprint:"))
+        .map(|fingerprint| fingerprint.trim().to_uppercase())
+}
+
+/// The close callback only fires once, so whichever wait sees it remembers it for the others
+struct CloseEvent {
+    close_rx: mpsc::Receiver<()>,
+    closed: bool,
+}
+
+impl CloseEvent {
+    async fn recv(&mut self) -> Option<()> {
+        if !self.closed {
+            self.close_rx.recv().await?;
+            self.closed = true;
+        }
+        Some(())
+    }
 }
 
 async fn recv_data_error(error_rx: &mut mpsc::Receiver<ffi::Error>) -> DataConnectionError {
@@ -1,6 +1,7 @@
 use leptos::spawn_local;
 use tokio::sync::{broadcast, mpsc};
 use wasm_bindgen::prelude::*;
+use web_sys::{RtcDataChannel, RtcPeerConnection};
 
 use super::{client::ClientError, CHANNEL_BUFFER_SIZE};
 
@@ -55,6 +56,15 @@ extern "C" {
     #[wasm_bindgen(method, getter)]
     pub fn peer(this: &DataConnection) -> String;
 
+    #[wasm_bindgen(method, getter)]
+    pub fn label(this: &DataConnection) -> String;
+
+    #[wasm_bindgen(method, getter, js_name = "peerConnection")]
+    pub fn peer_connection(this: &DataConnection) -> Option<RtcPeerConnection>;
+
+    #[wasm_bindgen(method, getter, js_name = "dataChannel")]
+    pub fn data_channel(this: &DataConnection) -> Option<RtcDataChannel>;
+
     pub type Error;
 
     #[wasm_bindgen(method, getter = type)]
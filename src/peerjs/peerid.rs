@@ -1,6 +1,7 @@
 use rand::{thread_rng, Rng};
 
 const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+const PREFIX: &str = "camas-file-transfer-";
 
 #[derive(Clone)]
 pub struct PeerID {
@@ -14,11 +15,16 @@ impl PeerID {
             return None;
         }
 
-        let full_id = format!("camas-file-transfer-{base_id}");
+        let full_id = format!("{PREFIX}{base_id}");
 
         Some(PeerID { base_id, full_id })
     }
 
+    /// Parses a full ID, such as one a peer connected from
+    pub fn from_full(full_id: &str) -> Option<PeerID> {
+        PeerID::new(full_id.strip_prefix(PREFIX)?.to_string())
+    }
+
     pub fn new_random_short_id() -> PeerID {
         let base_id = random_alphabet_string(4);
         PeerID::new(base_id).unwrap()
@@ -0,0 +1,497 @@
+use std::{
+    future::{poll_fn, Future},
+    task::Poll,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compression::Codec,
+    crypto::{Cipher, DecryptError, PasswordExchange, PasswordExchangeError, ShareKey},
+    peerjs::dataconnection::{DataConnection, DataConnectionError},
+    utils::timeout,
+};
+
+/// Version of the messages below. Only raised when an existing message changes shape. Features
+/// that just add messages are announced as capabilities instead
+pub const PROTOCOL_VERSION: u32 = 9;
+/// Oldest version this page still talks to. Every version from it on decodes the messages it
+/// knows the same way
+pub const MIN_PROTOCOL_VERSION: u32 = 9;
+/// Whether the peer passes chunks on to other receivers of a share. A receiver only announces it
+/// while seeding is turned on in its settings
+pub const CAPABILITY_SWARM: &str = "swarm";
+/// How long to wait for the peer's hello. Peers from before the handshake never send one
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Label of the connection messages are sent over
+pub const CONTROL_LABEL: &str = "control";
+/// Label of the control connection opened from a link without the key. Messages on it are
+/// encrypted with [`Cipher::keyless`] until the password exchange
+pub const KEYLESS_CONTROL_LABEL: &str = "keyless-control";
+/// Label of the extra connections a peer opens to spread chunks across
+pub const CHUNKS_LABEL: &str = "chunks";
+/// Label of the connections receivers of a share open to each other to fetch chunks
+pub const SEED_LABEL: &str = "seed";
+/// Most connections to one peer, including the control connection
+pub const MAX_CONNECTIONS: u32 = 8;
+
+/// Size of the slices a file is split into. Each slice is sent as its own message
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+/// Number of chunks the sender sends ahead of the last acknowledged one, for each connection
+pub const ACK_WINDOW: u64 = 16;
+/// Most chunks a receiver is pointed at another receiver for at once
+pub const MAX_SEED_CHUNKS: u64 = 4096;
+
+/// New variants go at the end, and are only sent to peers that announce the capability they
+/// belong to, so a peer never receives a message it can't decode
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+    /// Sent by both peers as soon as the connection opens. It has to stay the first variant and
+    /// keep its fields, so that every version can decode it
+    Hello(Hello),
+    /// Sent by both peers right after the hello, with how many connections they'd like to use.
+    /// The lower of the two is used
+    Connections(u32),
+    /// Sent by the sender once every connection is open, saying whether the share needs a
+    /// password. If it does, nothing about the files is sent until the password exchange succeeds
+    PasswordRequired(bool),
+    /// Sent by both peers with their side of the password exchange
+    PasswordExchange(Vec<u8>),
+    /// Sent by both peers with the cipher from the password exchange. The peer can only read it
+    /// if both typed the same password
+    PasswordConfirmed,
+    Manifest(Manifest),
+    Request(TransferRequest),
+    Chunk(Chunk),
+    Ack(Ack),
+    Resend(Resend),
+    /// Sent by the receiver instead of a transfer request when the user turns the share down
+    Decline,
+    /// Sent by the sender instead of the file list when the user turns the connection away
+    Deny,
+    /// Sent by either peer when it stops the transfer, such as when the page is closed
+    Cancel,
+    /// Sent by either peer to hold off on sending chunks until it's resumed
+    Pause,
+    Resume,
+    /// Sent by either peer when it gives up on the connection, with the reason
+    Error(String),
+    /// Sent by the receiver for each file once every file has been saved
+    Done(Done),
+    /// Sent by the sender while the receiver waits for its turn, with its place in the queue,
+    /// starting from 1
+    Queued(u32),
+    /// Sent by the sender to every peer when the share closes because its policy ran out
+    Ended(ShareEnd),
+    /// Sent by the sender in answer to a transfer request asking for a seeder
+    Seeder(Seeder),
+    /// Sent by a receiver to another receiver of the same share, over a seed connection
+    SeedRequest(SeedRequest),
+}
+
+/// Why the sender closed the share
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, thiserror::Error)]
+pub enum ShareEnd {
+    #[error("The share expired")]
+    Expired,
+    #[error("The share reached its download limit")]
+    DownloadLimit,
+    #[error("The share was for one download only")]
+    Burned,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    /// Names of the optional features the peer supports. Names it doesn't know are ignored
+    pub capabilities: Vec<String>,
+}
+
+/// Sent first, listing every file in the share. Nothing else is sent until the receiver accepts
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: Vec<FileHeader>,
+    /// Text shared instead of files. It's shown to the receiver as is, so nothing is requested
+    /// and `files` is empty
+    pub text: Option<String>,
+    /// Message from the sender, shown alongside the files
+    pub note: Option<String>,
+    /// Compression codecs the sender can use, most preferred first
+    pub codecs: Vec<Codec>,
+    /// Set when receivers may pass chunks on to each other. Seed connections are encrypted with
+    /// it, so only receivers let into the share can use them
+    pub swarm_key: Option<ShareKey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileHeader {
+    /// Path relative to the shared folder, separated by `/`. Just the file name when a folder
+    /// isn't being shared
+    pub path: String,
+    pub size: u64,
+    pub mime_type: String,
+    /// SHA-256 of the whole file
+    pub hash: [u8; 32],
+    /// Root of the Merkle tree over the file's chunks, which each chunk is verified against
+    pub merkle_root: [u8; 32],
+    pub chunk_count: u64,
+}
+
+/// Sent by the receiver once it's ready for the data of a file. `from_chunk` is non-zero when
+/// resuming a partial download
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferRequest {
+    pub file: u32,
+    pub from_chunk: u64,
+    /// Picked by the receiver from the codecs in the manifest. The sender may still send a file
+    /// uncompressed, such as when it's already compressed
+    pub codec: Codec,
+    /// Whether the receiver keeps the chunks to pass on to other receivers
+    pub seeding: bool,
+    /// Asks for a receiver to fetch the chunks from instead. The sender answers with a seeder
+    /// rather than the chunks
+    pub find_seeder: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Chunk {
+    pub file: u32,
+    pub index: u64,
+    /// How `data` is compressed. The proof and the file's hashes are over the uncompressed data
+    pub codec: Codec,
+    pub data: Vec<u8>,
+    /// Proves the chunk belongs under the file's Merkle root
+    pub proof: Vec<[u8; 32]>,
+}
+
+/// Another receiver holding the chunks from `from_chunk` on. `peer_id` is `None` when no other
+/// receiver is ahead, and the file has to come from the sender
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Seeder {
+    pub file: u32,
+    pub from_chunk: u64,
+    /// Full PeerJS ID of the seeder
+    pub peer_id: Option<String>,
+    /// End of the chunks to fetch from the seeder
+    pub to_chunk: u64,
+}
+
+/// Asks a seeder for chunks `from_chunk..to_chunk` of the file at `file` in the manifest, which
+/// it saved under `key`. The seeder sends them in order and uncompressed, each with the proof it
+/// was received with, so a seeder can't pass on anything the sender didn't send
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SeedRequest {
+    pub key: String,
+    pub file: u32,
+    pub from_chunk: u64,
+    pub to_chunk: u64,
+}
+
+/// Sent by the receiver once a chunk has been saved. Chunks are acknowledged in order
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ack {
+    pub file: u32,
+    pub index: u64,
+}
+
+/// Sent by the receiver when a chunk fails verification. The sender goes back to that chunk, and
+/// the receiver ignores copies of chunks it already has
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Resend {
+    pub file: u32,
+    pub index: u64,
+}
+
+/// What the receiver actually saved of a file, for the sender to check against what it sent
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Done {
+    pub file: u32,
+    pub size: u64,
+    /// SHA-256 of the saved file
+    pub hash: [u8; 32],
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("{0}")]
+    DataConnectionError(DataConnectionError),
+    #[error("{0}")]
+    DecryptFailed(DecryptError),
+    #[error("Couldn't decode message: {0}")]
+    DecodeError(postcard::Error),
+    #[error("Peer reported an error: {0}")]
+    PeerError(String),
+    #[error("Cancelled by {0}")]
+    Cancelled(&'static str),
+    #[error("{0}")]
+    ShareEnded(ShareEnd),
+}
+
+/// Every connection to one peer. Messages go over the first, and chunks are spread across all of
+/// them
+pub struct Link {
+    connections: Vec<DataConnection>,
+    /// How the peer is described to the user, such as "sender"
+    peer_name: &'static str,
+    /// Index of the connection the next chunk is sent over
+    next: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("Error while waiting for the peer's hello: {0}")]
+    ReceiveError(ProtocolError),
+    #[error("Expected the number of connections to use but received a different message")]
+    NoConnectionCount,
+    #[error("Peer didn't say hello. It's likely running an older version of this page")]
+    TimedOut,
+    #[error("Peer is running an incompatible version of this page")]
+    Incompatible,
+    #[error(
+        "Peer uses protocol version {0}, but this page needs version {MIN_PROTOCOL_VERSION} or \
+         later. Reload both pages to get the same version"
+    )]
+    VersionMismatch(u32),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PasswordError {
+    #[error("Error during the password exchange: {0}")]
+    ReceiveError(ProtocolError),
+    #[error("Expected the password exchange but received a different message")]
+    UnexpectedMessage,
+    #[error("{0}")]
+    ExchangeError(PasswordExchangeError),
+    #[error("Wrong password")]
+    WrongPassword,
+}
+
+impl Hello {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|name| name == capability)
+    }
+}
+
+impl FileHeader {
+    pub fn new(
+        path: String,
+        size: u64,
+        mime_type: String,
+        hash: [u8; 32],
+        merkle_root: [u8; 32],
+    ) -> FileHeader {
+        FileHeader {
+            path,
+            size,
+            mime_type,
+            hash,
+            merkle_root,
+            chunk_count: size.div_ceil(CHUNK_SIZE),
+        }
+    }
+
+    /// Number of bytes in the chunks before `chunk`
+    pub fn bytes_before(&self, chunk: u64) -> u64 {
+        (chunk * CHUNK_SIZE).min(self.size)
+    }
+
+    pub fn chunk_len(&self, chunk: u64) -> u64 {
+        self.bytes_before(chunk + 1) - self.bytes_before(chunk)
+    }
+}
+
+impl Link {
+    pub fn new(control: DataConnection, peer_name: &'static str) -> Link {
+        Link {
+            connections: vec![control],
+            peer_name,
+            next: 0,
+        }
+    }
+
+    /// The connection messages other than chunks are sent over
+    pub fn control(&mut self) -> &mut DataConnection {
+        &mut self.connections[0]
+    }
+
+    pub fn add_connection(&mut self, connection: DataConnection) {
+        self.connections.push(connection);
+    }
+
+    /// Number of chunks the sender sends ahead of the last acknowledged one. It grows with the
+    /// connections, so each one can be kept busy
+    pub fn ack_window(&self) -> u64 {
+        ACK_WINDOW * self.connections.len() as u64
+    }
+}
+
+pub fn send_message(link: &Link, cipher: &Cipher, message: &Message) {
+    send_over(&link.connections[0], cipher, message);
+}
+
+/// Sends a chunk over the next connection in turn, once that connection has room for it
+pub async fn send_chunk(
+    link: &mut Link,
+    cipher: &Cipher,
+    chunk: Chunk,
+) -> Result<(), DataConnectionError> {
+    let index = link.next;
+    link.next = (index + 1) % link.connections.len();
+
+    let connection = &mut link.connections[index];
+    connection.wait_for_buffer_low().await?;
+    send_over(connection, cipher, &Message::Chunk(chunk));
+
+    Ok(())
+}
+
+fn send_over(connection: &DataConnection, cipher: &Cipher, message: &Message) {
+    let bytes = postcard::to_allocvec(message).unwrap();
+    connection.send_bytes(&cipher.encrypt(&bytes));
+}
+
+/// Receives a message from the control connection
+pub async fn receive_message(link: &mut Link, cipher: &Cipher) -> Result<Message, ProtocolError> {
+    let bytes = link
+        .control()
+        .receive_bytes()
+        .await
+        .map_err(ProtocolError::DataConnectionError)?;
+
+    decode_message(cipher, &bytes, link.peer_name)
+}
+
+/// Receives a message from whichever connection has one first. Each connection is ordered on
+/// its own, so chunks can arrive out of order
+pub async fn receive_any(link: &mut Link, cipher: &Cipher) -> Result<Message, ProtocolError> {
+    let mut receives = link
+        .connections
+        .iter_mut()
+        .map(|connection| Box::pin(connection.receive_bytes()))
+        .collect::<Vec<_>>();
+    let bytes = poll_fn(|cx| {
+        receives
+            .iter_mut()
+            .find_map(|receive| match receive.as_mut().poll(cx) {
+                Poll::Ready(result) => Some(result),
+                Poll::Pending => None,
+            })
+            .map_or(Poll::Pending, Poll::Ready)
+    })
+    .await
+    .map_err(ProtocolError::DataConnectionError)?;
+
+    decode_message(cipher, &bytes, link.peer_name)
+}
+
+fn decode_message(
+    cipher: &Cipher,
+    bytes: &[u8],
+    peer_name: &'static str,
+) -> Result<Message, ProtocolError> {
+    let bytes = cipher
+        .decrypt(bytes)
+        .map_err(ProtocolError::DecryptFailed)?;
+
+    // The peer giving up ends whatever was waiting on it
+    match postcard::from_bytes(&bytes).map_err(ProtocolError::DecodeError)? {
+        Message::Error(reason) => Err(ProtocolError::PeerError(reason)),
+        Message::Cancel => Err(ProtocolError::Cancelled(peer_name)),
+        Message::Ended(end) => Err(ProtocolError::ShareEnded(end)),
+        message => Ok(message),
+    }
+}
+
+/// Exchanges hellos with the peer, announcing `capabilities`, and checks it speaks a compatible
+/// version. Returns the peer's hello
+pub async fn handshake(
+    link: &mut Link,
+    cipher: &Cipher,
+    capabilities: &[&str],
+) -> Result<Hello, HandshakeError> {
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: capabilities.iter().map(|name| name.to_string()).collect(),
+    };
+    send_message(link, cipher, &Message::Hello(hello));
+
+    let message = timeout(HELLO_TIMEOUT, receive_message(link, cipher))
+        .await
+        .map_err(|_| HandshakeError::TimedOut)?;
+    // Pages from before the handshake send raw strings or unencrypted data, which can't be cast,
+    // decrypted or decoded as a message
+    let hello = match message {
+        Ok(Message::Hello(hello)) => hello,
+        Ok(_)
+        | Err(ProtocolError::DataConnectionError(DataConnectionError::InvalidCast(_)))
+        | Err(ProtocolError::DecryptFailed(_))
+        | Err(ProtocolError::DecodeError(_)) => return Err(HandshakeError::Incompatible),
+        Err(error) => return Err(HandshakeError::ReceiveError(error)),
+    };
+    // A newer peer decides for itself whether it still talks to this page. An older one is told
+    // why it's turned away, as it has no way of knowing
+    if hello.version < MIN_PROTOCOL_VERSION {
+        send_message(
+            link,
+            cipher,
+            &Message::Error(format!(
+                "Peer needs protocol version {MIN_PROTOCOL_VERSION} or later, but this page uses \
+                 version {}. Reload both pages to get the same version",
+                hello.version
+            )),
+        );
+        return Err(HandshakeError::VersionMismatch(hello.version));
+    }
+
+    Ok(hello)
+}
+
+/// Agrees with the peer on how many connections to use, at most `wanted`
+pub async fn negotiate_connections(
+    link: &mut Link,
+    cipher: &Cipher,
+    wanted: u32,
+) -> Result<u32, HandshakeError> {
+    send_message(link, cipher, &Message::Connections(wanted));
+
+    let Message::Connections(peer_wanted) = receive_message(link, cipher)
+        .await
+        .map_err(HandshakeError::ReceiveError)?
+    else {
+        return Err(HandshakeError::NoConnectionCount);
+    };
+
+    Ok(wanted.min(peer_wanted).clamp(1, MAX_CONNECTIONS))
+}
+
+/// Runs the password exchange with the peer. Returns the cipher for everything after it, which
+/// the link alone can't decrypt
+pub async fn exchange_password(
+    link: &mut Link,
+    cipher: &Cipher,
+    password: &str,
+) -> Result<Cipher, PasswordError> {
+    let (exchange, message) = PasswordExchange::start(password);
+    send_message(link, cipher, &Message::PasswordExchange(message));
+
+    let Message::PasswordExchange(peer_message) = receive_message(link, cipher)
+        .await
+        .map_err(PasswordError::ReceiveError)?
+    else {
+        return Err(PasswordError::UnexpectedMessage);
+    };
+    let password_key = exchange
+        .finish(&peer_message)
+        .map_err(PasswordError::ExchangeError)?;
+    let cipher = cipher.with_password_key(&password_key);
+
+    // Different passwords give different keys, so the peer's confirmation won't decrypt
+    send_message(link, &cipher, &Message::PasswordConfirmed);
+    match receive_message(link, &cipher).await {
+        Ok(Message::PasswordConfirmed) => Ok(cipher),
+        Ok(_) => Err(PasswordError::UnexpectedMessage),
+        Err(ProtocolError::DecryptFailed(_)) => Err(PasswordError::WrongPassword),
+        Err(error) => Err(PasswordError::ReceiveError(error)),
+    }
+}
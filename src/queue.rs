@@ -0,0 +1,102 @@
+use std::{cell::RefCell, collections::VecDeque, future::Future, rc::Rc};
+
+use tokio::sync::Notify;
+
+/// Limits how many peers are sent to at once. The rest wait their turn, in the order they joined
+#[derive(Clone)]
+pub struct UploadQueue {
+    state: Rc<RefCell<QueueState>>,
+    changed: Rc<Notify>,
+}
+
+struct QueueState {
+    max_uploads: u32,
+    uploading: u32,
+    /// Tickets waiting for a turn, by ID, first in line first
+    waiting: VecDeque<u64>,
+    next_id: u64,
+}
+
+/// A place in the queue, which becomes an upload slot once it's its turn. Dropping the ticket
+/// gives up either, letting the next in line go
+pub struct QueueTicket {
+    queue: UploadQueue,
+    id: u64,
+    uploading: bool,
+}
+
+impl UploadQueue {
+    pub fn new(max_uploads: u32) -> UploadQueue {
+        UploadQueue {
+            state: Rc::new(RefCell::new(QueueState {
+                max_uploads,
+                uploading: 0,
+                waiting: VecDeque::new(),
+                next_id: 0,
+            })),
+            changed: Rc::new(Notify::new()),
+        }
+    }
+
+    /// Joins the back of the queue
+    pub fn join(&self) -> QueueTicket {
+        let mut state = self.state.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.waiting.push_back(id);
+
+        QueueTicket {
+            queue: self.clone(),
+            id,
+            uploading: false,
+        }
+    }
+}
+
+impl QueueTicket {
+    /// Takes an upload slot if it's this ticket's turn. Otherwise returns its place in the queue,
+    /// starting from 1
+    pub fn try_take(&mut self) -> Result<(), u32> {
+        if self.uploading {
+            return Ok(());
+        }
+
+        let mut state = self.queue.state.borrow_mut();
+        let position = state
+            .waiting
+            .iter()
+            .position(|id| *id == self.id)
+            .expect("waiting tickets stay in the queue");
+        if position > 0 || state.uploading >= state.max_uploads {
+            return Err(position as u32 + 1);
+        }
+
+        state.waiting.pop_front();
+        state.uploading += 1;
+        self.uploading = true;
+        drop(state);
+        // Everyone behind moves up a place
+        self.queue.changed.notify_waiters();
+
+        Ok(())
+    }
+
+    /// Waits until the queue changes, after which `try_take` may give a different answer
+    pub fn changed(&self) -> impl Future<Output = ()> {
+        let changed = self.queue.changed.clone();
+        async move { changed.notified().await }
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.borrow_mut();
+        if self.uploading {
+            state.uploading -= 1;
+        } else {
+            state.waiting.retain(|id| *id != self.id);
+        }
+        drop(state);
+        self.queue.changed.notify_waiters();
+    }
+}
@@ -0,0 +1,231 @@
+use js_sys::{Object, Promise, Reflect};
+use leptos::window;
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    FileSystemDirectoryHandle, FileSystemFileHandle, FileSystemGetDirectoryOptions,
+    FileSystemGetFileOptions,
+};
+
+use crate::{protocol::FileHeader, sink::Sink, zip::ZipWriter};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(catch, js_name = showDirectoryPicker)]
+    fn show_directory_picker(options: &JsValue) -> Result<Promise, JsValue>;
+
+    #[wasm_bindgen(catch, js_name = showSaveFilePicker)]
+    fn show_save_file_picker(options: &JsValue) -> Result<Promise, JsValue>;
+}
+
+/// Where received files end up. Each file is written as it arrives, so it never has to be held
+/// in memory
+pub enum SaveTarget {
+    /// The only file in the share, written where the user picked
+    File(FileSystemFileHandle),
+    /// Each file is downloaded on its own
+    Downloads,
+    /// Files are written into one archive
+    Zip(ZipWriter),
+    /// Files are written into a folder picked by the user, keeping their relative paths
+    Directory(FileSystemDirectoryHandle),
+}
+
+/// Writes a single file of the share
+pub enum FileWriter<'a> {
+    Sink(Sink),
+    Zip(&'a mut ZipWriter),
+}
+
+impl SaveTarget {
+    pub async fn create(&mut self, header: &FileHeader) -> Result<FileWriter<'_>, JsValue> {
+        let path = sanitize_path(&header.path);
+
+        let sink = match self {
+            SaveTarget::File(file) => Sink::writable(file).await?,
+            SaveTarget::Downloads => {
+                let filename = path.rsplit('/').next().unwrap();
+                Sink::download(filename, Some(header.size), &header.mime_type).await
+            }
+            SaveTarget::Zip(zip) => {
+                zip.start_entry(&path, header.size).await?;
+                return Ok(FileWriter::Zip(zip));
+            }
+            SaveTarget::Directory(directory) => {
+                Sink::writable(&file_in_directory(directory, &path).await?).await?
+            }
+        };
+
+        Ok(FileWriter::Sink(sink))
+    }
+
+    pub async fn finish(self) -> Result<(), JsValue> {
+        match self {
+            SaveTarget::Zip(zip) => zip.finish().await,
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FileWriter<'_> {
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        match self {
+            FileWriter::Sink(sink) => sink.write(data).await,
+            FileWriter::Zip(zip) => zip.write(data).await,
+        }
+    }
+
+    pub async fn close(self) -> Result<(), JsValue> {
+        match self {
+            FileWriter::Sink(sink) => sink.close().await,
+            FileWriter::Zip(zip) => zip.finish_entry().await,
+        }
+    }
+}
+
+/// Whether the browser lets pages write files where the user picks. Without it, files are
+/// streamed as downloads instead
+pub fn save_pickers_supported() -> bool {
+    Reflect::has(&window(), &"showSaveFilePicker".into()).unwrap_or(false)
+        && Reflect::has(&window(), &"showDirectoryPicker".into()).unwrap_or(false)
+}
+
+pub async fn pick_directory() -> Result<FileSystemDirectoryHandle, JsValue> {
+    let options = Object::new();
+    Reflect::set(&options, &"mode".into(), &"readwrite".into()).unwrap();
+
+    let directory = JsFuture::from(show_directory_picker(&options)?).await?;
+
+    Ok(directory.unchecked_into())
+}
+
+pub async fn pick_save_file(suggested_name: &str) -> Result<FileSystemFileHandle, JsValue> {
+    let options = Object::new();
+    Reflect::set(
+        &options,
+        &"suggestedName".into(),
+        &sanitize_path(suggested_name)
+            .rsplit('/')
+            .next()
+            .unwrap()
+            .into(),
+    )
+    .unwrap();
+
+    let file = JsFuture::from(show_save_file_picker(&options)?).await?;
+
+    Ok(file.unchecked_into())
+}
+
+/// Name for an archive of the given paths. Uses the folder they all share, if there is one
+pub fn zip_name<'a>(mut paths: impl Iterator<Item = &'a str>) -> String {
+    let root = |path: &'a str| path.split_once('/').map(|(root, _)| root);
+
+    let Some(first_root) = paths.next().and_then(root) else {
+        return "files.zip".to_string();
+    };
+    if paths.all(|path| root(path) == Some(first_root)) {
+        format!("{}.zip", sanitize_path(first_root))
+    } else {
+        "files.zip".to_string()
+    }
+}
+
+/// Paths come from the other peer, so anything that could escape the target folder is dropped
+fn sanitize_path(path: &str) -> String {
+    let path = path
+        .split(['/', '\\'])
+        .filter(|component| !matches!(*component, "" | "." | ".."))
+        .collect::<Vec<_>>()
+        .join("/");
+
+    if path.is_empty() {
+        "file".to_string()
+    } else {
+        path
+    }
+}
+
+/// Returns the file at `path` under `root`, creating it and any missing folders
+async fn file_in_directory(
+    root: &FileSystemDirectoryHandle,
+    path: &str,
+) -> Result<FileSystemFileHandle, JsValue> {
+    let mut components = path.split('/').collect::<Vec<_>>();
+    let filename = components.pop().unwrap();
+
+    let mut directory_options = FileSystemGetDirectoryOptions::new();
+    directory_options.create(true);
+    let mut directory = root.clone();
+    for component in components {
+        directory = JsFuture::from(
+            directory.get_directory_handle_with_options(component, &directory_options),
+        )
+        .await?
+        .unchecked_into();
+    }
+
+    let mut file_options = FileSystemGetFileOptions::new();
+    file_options.create(true);
+    let file =
+        JsFuture::from(directory.get_file_handle_with_options(filename, &file_options)).await?;
+
+    Ok(file.unchecked_into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_plain_paths() {
+        assert_eq!(sanitize_path("photo.jpg"), "photo.jpg");
+        assert_eq!(
+            sanitize_path("holiday/day 1/photo.jpg"),
+            "holiday/day 1/photo.jpg"
+        );
+    }
+
+    #[test]
+    fn drops_parent_segments() {
+        assert_eq!(sanitize_path("../secret"), "secret");
+        assert_eq!(sanitize_path("a/../../b/.."), "a/b");
+        assert_eq!(sanitize_path("a/./b"), "a/b");
+    }
+
+    #[test]
+    fn strips_absolute_paths() {
+        assert_eq!(sanitize_path("/etc/passwd"), "etc/passwd");
+        assert_eq!(sanitize_path("\\Windows\\System32"), "Windows/System32");
+    }
+
+    #[test]
+    fn splits_on_backslashes() {
+        assert_eq!(sanitize_path("a\\b"), "a/b");
+        assert_eq!(sanitize_path("..\\..\\x"), "x");
+        assert_eq!(sanitize_path("a/..\\b"), "a/b");
+    }
+
+    #[test]
+    fn drops_empty_segments() {
+        assert_eq!(sanitize_path("a//b/"), "a/b");
+    }
+
+    #[test]
+    fn falls_back_when_nothing_is_left() {
+        assert_eq!(sanitize_path(""), "file");
+        assert_eq!(sanitize_path("../.."), "file");
+        assert_eq!(sanitize_path("/./\\"), "file");
+    }
+
+    #[test]
+    fn zip_named_after_shared_folder() {
+        assert_eq!(
+            zip_name(["photos/a.jpg", "photos/b/c.jpg"].into_iter()),
+            "photos.zip"
+        );
+        assert_eq!(zip_name(["a/x", "b/y"].into_iter()), "files.zip");
+        assert_eq!(zip_name(["x"].into_iter()), "files.zip");
+        assert_eq!(zip_name(["../a", "../b"].into_iter()), "file.zip");
+    }
+}
@@ -0,0 +1,234 @@
+use js_sys::{Array, Object, Reflect, Uint8Array};
+use leptos::{document, window};
+use log::warn;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    Blob, BlobPropertyBag, FileSystemFileHandle, FileSystemWritableFileStream, HtmlAnchorElement,
+    HtmlIFrameElement, MessageChannel, MessageEvent, MessagePort, ServiceWorkerRegistration, Url,
+};
+
+use crate::utils::jserror;
+
+const SERVICE_WORKER_PATH: &str = "sw.js";
+
+/// Somewhere a file's bytes are written to as they arrive. Dropped before `close` is called, the
+/// partly written file is thrown away
+pub struct Sink {
+    inner: Option<SinkInner>,
+}
+
+enum SinkInner {
+    /// Written straight to a file picked by the user
+    Writable(FileSystemWritableFileStream),
+    /// Streamed to a download through the service worker
+    Stream(StreamDownload),
+    /// Collected into a blob and downloaded once complete. Only used when the browser supports
+    /// neither of the others
+    Blob {
+        filename: String,
+        mime_type: String,
+        parts: Array,
+    },
+}
+
+impl Sink {
+    pub async fn writable(file: &FileSystemFileHandle) -> Result<Sink, JsValue> {
+        let writable = JsFuture::from(file.create_writable())
+            .await?
+            .unchecked_into::<FileSystemWritableFileStream>();
+
+        Ok(Sink::new(SinkInner::Writable(writable)))
+    }
+
+    /// Starts a download, streamed through the service worker if possible
+    pub async fn download(filename: &str, size: Option<u64>, mime_type: &str) -> Sink {
+        match StreamDownload::start(filename, size, mime_type).await {
+            Ok(download) => Sink::new(SinkInner::Stream(download)),
+            Err(error) => {
+                jserror!("Couldn't stream download, keeping it in memory: {}", error);
+                Sink::new(SinkInner::Blob {
+                    filename: filename.to_string(),
+                    mime_type: mime_type.to_string(),
+                    parts: Array::new(),
+                })
+            }
+        }
+    }
+
+    fn new(inner: SinkInner) -> Sink {
+        Sink { inner: Some(inner) }
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        match self.inner.as_mut().unwrap() {
+            SinkInner::Writable(writable) => {
+                JsFuture::from(writable.write_with_u8_array(data)?).await?;
+            }
+            SinkInner::Stream(download) => download.write(data).await?,
+            SinkInner::Blob { parts, .. } => {
+                let chunk = Array::of1(&Uint8Array::from(data));
+                let blob = Blob::new_with_u8_array_sequence(&chunk)?;
+                parts.push(&blob);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn close(mut self) -> Result<(), JsValue> {
+        match self.inner.take().unwrap() {
+            SinkInner::Writable(writable) => {
+                JsFuture::from(writable.close()).await?;
+            }
+            SinkInner::Stream(download) => download.end(),
+            SinkInner::Blob {
+                filename,
+                mime_type,
+                parts,
+            } => {
+                let mut options = BlobPropertyBag::new();
+                options.type_(&mime_type);
+                let blob = Blob::new_with_blob_sequence_and_options(&parts, &options)?;
+                download_blob(&filename, &blob);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        match self.inner.take() {
+            Some(SinkInner::Writable(writable)) => {
+                let _ = writable.abort();
+            }
+            Some(SinkInner::Stream(download)) => download.abort(),
+            Some(SinkInner::Blob { .. }) | None => {}
+        }
+    }
+}
+
+/// A download served by the service worker, which streams whatever is posted to `port`
+struct StreamDownload {
+    port: MessagePort,
+    message_rx: mpsc::UnboundedReceiver<String>,
+    _on_message: Closure<dyn Fn(MessageEvent)>,
+}
+
+impl StreamDownload {
+    async fn start(
+        filename: &str,
+        size: Option<u64>,
+        mime_type: &str,
+    ) -> Result<StreamDownload, JsValue> {
+        let navigator = window().navigator();
+        if !Reflect::has(&navigator, &"serviceWorker".into())? {
+            return Err("Service workers unavailable".into());
+        }
+        let container = navigator.service_worker();
+        JsFuture::from(container.register(SERVICE_WORKER_PATH)).await?;
+        let registration = JsFuture::from(container.ready()?)
+            .await?
+            .unchecked_into::<ServiceWorkerRegistration>();
+        let worker = registration
+            .active()
+            .ok_or_else(|| JsValue::from("No active service worker"))?;
+
+        let channel = MessageChannel::new()?;
+        let port = channel.port1();
+        let (message_tx, message_rx) = mpsc::unbounded_channel();
+        let on_message = Closure::<dyn Fn(MessageEvent)>::new(move |event: MessageEvent| {
+            if let Some(message) = event.data().as_string() {
+                let _ = message_tx.send(message);
+            }
+        });
+        port.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        let id = Uuid::new_v4().to_string();
+        let message = Object::new();
+        Reflect::set(&message, &"type".into(), &"download".into())?;
+        Reflect::set(&message, &"id".into(), &id.as_str().into())?;
+        Reflect::set(&message, &"filename".into(), &filename.into())?;
+        if let Some(size) = size {
+            Reflect::set(&message, &"size".into(), &(size as f64).into())?;
+        }
+        Reflect::set(&message, &"mimeType".into(), &mime_type.into())?;
+        worker.post_message_with_transferable(&message, &Array::of1(&channel.port2()))?;
+
+        let mut download = StreamDownload {
+            port,
+            message_rx,
+            _on_message: on_message,
+        };
+        download.wait_for("ready").await?;
+
+        // Navigating a hidden frame to the download starts it without leaving the page
+        let base_uri = document().base_uri()?.unwrap_or_default();
+        let frame = document()
+            .create_element("iframe")?
+            .unchecked_into::<HtmlIFrameElement>();
+        frame.set_hidden(true);
+        frame.set_src(&format!("{base_uri}download/{id}"));
+        document().body().unwrap().append_child(&frame)?;
+
+        Ok(download)
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        let buffer = Uint8Array::from(data).buffer();
+        self.port
+            .post_message_with_transferable(&buffer, &Array::of1(&buffer))?;
+
+        self.wait_for("pulled").await
+    }
+
+    fn end(self) {
+        let _ = self.port.post_message(&"end".into());
+    }
+
+    fn abort(self) {
+        let _ = self.port.post_message(&"abort".into());
+    }
+
+    async fn wait_for(&mut self, expected: &str) -> Result<(), JsValue> {
+        match self.message_rx.recv().await {
+            Some(message) if message == expected => Ok(()),
+            Some(message) if message == "cancelled" => Err("Download cancelled".into()),
+            Some(message) => {
+                warn!("Unexpected message from service worker: {message}");
+                Err("Unexpected message from service worker".into())
+            }
+            None => Err("Service worker channel closed".into()),
+        }
+    }
+}
+
+pub fn download_blob(filename: &str, blob: &Blob) {
+    let url = Url::create_object_url_with_blob(blob).unwrap();
+
+    let anchor_element = document()
+        .create_element("a")
+        .unwrap()
+        .dyn_into::<HtmlAnchorElement>()
+        .unwrap();
+    anchor_element.set_href(&url);
+    anchor_element.set_attribute("download", filename).unwrap();
+
+    document()
+        .body()
+        .unwrap()
+        .append_child(&anchor_element)
+        .unwrap();
+
+    anchor_element.click();
+
+    document()
+        .body()
+        .unwrap()
+        .remove_child(&anchor_element)
+        .unwrap();
+}
@@ -1,7 +1,10 @@
 use std::{fmt, future::Future, time::Duration};
 
+use js_sys::Promise;
 use leptos::set_timeout;
 use tokio::sync::oneshot;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
 
 macro_rules! jserror {
     ($message:expr, $js_error:expr) => {{
@@ -13,6 +16,12 @@ macro_rules! jserror {
 
 pub(crate) use jserror;
 
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(catch, js_namespace = ["navigator", "clipboard"], js_name = writeText)]
+    fn write_text(text: &str) -> Result<Promise, JsValue>;
+}
+
 pub(crate) async fn sleep(duration: Duration) {
     let (callback_tx, callback_rx) = oneshot::channel();
 
@@ -36,6 +45,16 @@ pub(crate) async fn timeout<T, F: Future<Output = T>>(
     }
 }
 
+pub(crate) async fn copy_to_clipboard(text: &str) -> Result<(), JsValue> {
+    JsFuture::from(write_text(text)?).await?;
+
+    Ok(())
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) struct Elapsed;
 
@@ -46,3 +65,45 @@ impl fmt::Display for Elapsed {
         "timeout has elapsed".fmt(fmt)
     }
 }
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next_unit;
+    }
+
+    format!("{value:.1} {unit}")
+}
+
+/// Formats a place in a list, such as "3rd"
+pub(crate) fn format_ordinal(number: u32) -> String {
+    let suffix = match (number % 10, number % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+
+    format!("{number}{suffix}")
+}
+
+pub(crate) fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.ceil() as u64;
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, seconds) => format!("{seconds}s"),
+        (0, minutes, seconds) => format!("{minutes}m {seconds:02}s"),
+        (hours, minutes, _) => format!("{hours}h {minutes:02}m"),
+    }
+}
@@ -0,0 +1,98 @@
+use sha2::{Digest, Sha256};
+
+/// Number of emoji in a phrase. Each one carries 6 bits
+const PHRASE_LENGTH: usize = 7;
+
+const EMOJI: [(&str, &str); 64] = [
+    ("🐶", "Dog"),
+    ("🐱", "Cat"),
+    ("🦁", "Lion"),
+    ("🐎", "Horse"),
+    ("🦄", "Unicorn"),
+    ("🐷", "Pig"),
+    ("🐘", "Elephant"),
+    ("🐰", "Rabbit"),
+    ("🐼", "Panda"),
+    ("🐓", "Rooster"),
+    ("🐧", "Penguin"),
+    ("🐢", "Turtle"),
+    ("🐟", "Fish"),
+    ("🐙", "Octopus"),
+    ("🦋", "Butterfly"),
+    ("🌷", "Flower"),
+    ("🌳", "Tree"),
+    ("🌵", "Cactus"),
+    ("🍄", "Mushroom"),
+    ("🌏", "Globe"),
+    ("🌙", "Moon"),
+    ("☁️", "Cloud"),
+    ("🔥", "Fire"),
+    ("🍌", "Banana"),
+    ("🍎", "Apple"),
+    ("🍓", "Strawberry"),
+    ("🌽", "Corn"),
+    ("🍕", "Pizza"),
+    ("🎂", "Cake"),
+    ("❤️", "Heart"),
+    ("😀", "Smiley"),
+    ("🤖", "Robot"),
+    ("🎩", "Hat"),
+    ("👓", "Glasses"),
+    ("🔧", "Spanner"),
+    ("🎅", "Santa"),
+    ("👍", "Thumbs up"),
+    ("☂️", "Umbrella"),
+    ("⌛", "Hourglass"),
+    ("⏰", "Clock"),
+    ("🎁", "Gift"),
+    ("💡", "Light bulb"),
+    ("📕", "Book"),
+    ("✏️", "Pencil"),
+    ("📎", "Paperclip"),
+    ("✂️", "Scissors"),
+    ("🔒", "Lock"),
+    ("🔑", "Key"),
+    ("🔨", "Hammer"),
+    ("☎️", "Telephone"),
+    ("🏁", "Flag"),
+    ("🚂", "Train"),
+    ("🚲", "Bicycle"),
+    ("✈️", "Aeroplane"),
+    ("🚀", "Rocket"),
+    ("🏆", "Trophy"),
+    ("⚽", "Ball"),
+    ("🎸", "Guitar"),
+    ("🎺", "Trumpet"),
+    ("🔔", "Bell"),
+    ("⚓", "Anchor"),
+    ("🎧", "Headphones"),
+    ("📁", "Folder"),
+    ("📌", "Pin"),
+];
+
+/// Phrase both peers can read to each other to check nobody is sitting between them. Derived
+/// from the DTLS fingerprints of both ends of the connection, so each side gets the same phrase
+/// only if they're talking to each other directly
+pub fn verification_phrase(local_fingerprint: &str, remote_fingerprint: &str) -> String {
+    let (first, second) = if local_fingerprint <= remote_fingerprint {
+        (local_fingerprint, remote_fingerprint)
+    } else {
+        (remote_fingerprint, local_fingerprint)
+    };
+
+    let digest = Sha256::new()
+        .chain_update(b"file-transfer verification\n")
+        .chain_update(first)
+        .chain_update(b"\n")
+        .chain_update(second)
+        .finalize();
+    let bits = u64::from_be_bytes(digest[..8].try_into().unwrap());
+
+    (0..PHRASE_LENGTH)
+        .map(|i| {
+            let (emoji, name) = EMOJI[(bits >> (58 - i * 6)) as usize & 0x3f];
+            format!("{emoji} {name}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
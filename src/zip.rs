@@ -0,0 +1,567 @@
+use wasm_bindgen::JsValue;
+
+use crate::sink::Sink;
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// Bit 3, marking the CRC and sizes as following the data, and bit 11, marking the file name as
+/// UTF-8
+const FLAGS: u16 = (1 << 3) | (1 << 11);
+/// Stored, without compression
+const METHOD_STORED: u16 = 0;
+/// 1980-01-01 00:00, the earliest time that can be represented
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+/// Where an archive's bytes are written
+pub trait ZipOutput {
+    async fn write(&mut self, data: &[u8]) -> Result<(), JsValue>;
+
+    async fn close(self) -> Result<(), JsValue>;
+}
+
+impl ZipOutput for Sink {
+    async fn write(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        Sink::write(self, data).await
+    }
+
+    async fn close(self) -> Result<(), JsValue> {
+        Sink::close(self).await
+    }
+}
+
+/// Writes an uncompressed ZIP archive to a sink as the data arrives, so the archive can be larger
+/// than memory
+pub struct ZipWriter<W = Sink> {
+    output: W,
+    entries: Vec<Entry>,
+    /// The entry being written, and the CRC of its data so far
+    current: Option<(Entry, crc32fast::Hasher)>,
+    offset: u64,
+}
+
+struct Entry {
+    path: String,
+    size: u64,
+    crc32: u32,
+    header_offset: u64,
+}
+
+impl<W: ZipOutput> ZipWriter<W> {
+    pub fn new(output: W) -> ZipWriter<W> {
+        ZipWriter {
+            output,
+            entries: Vec::new(),
+            current: None,
+            offset: 0,
+        }
+    }
+
+    /// Starts the next entry. `size` is needed up front to know whether ZIP64 fields are needed
+    pub async fn start_entry(&mut self, path: &str, size: u64) -> Result<(), JsValue> {
+        let entry = Entry {
+            path: path.to_string(),
+            size,
+            crc32: 0,
+            header_offset: self.offset,
+        };
+
+        self.write_raw(&entry.local_header()).await?;
+        self.current = Some((entry, crc32fast::Hasher::new()));
+
+        Ok(())
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        let (_, hasher) = self.current.as_mut().unwrap();
+        hasher.update(data);
+
+        self.write_raw(data).await
+    }
+
+    /// Ends the current entry with a data descriptor holding its CRC
+    pub async fn finish_entry(&mut self) -> Result<(), JsValue> {
+        let (mut entry, hasher) = self.current.take().unwrap();
+        entry.crc32 = hasher.finalize();
+
+        self.write_raw(&entry.data_descriptor()).await?;
+        self.entries.push(entry);
+
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> Result<(), JsValue> {
+        let trailer = central_directory(&self.entries, self.offset);
+        self.write_raw(&trailer).await?;
+
+        self.output.close().await
+    }
+
+    async fn write_raw(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        self.output.write(data).await?;
+        self.offset += data.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl Entry {
+    fn zip64(&self) -> bool {
+        self.size >= u32::MAX as u64 || self.header_offset >= u32::MAX as u64
+    }
+
+    fn version(&self) -> u16 {
+        if self.zip64() {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFAULT
+        }
+    }
+
+    /// The CRC and sizes are left as zero, as they follow the data in the data descriptor
+    fn local_header(&self) -> Vec<u8> {
+        let mut extra = Vec::new();
+        let size = if self.zip64() {
+            put_u16(&mut extra, ZIP64_EXTRA_FIELD_ID);
+            put_u16(&mut extra, 16);
+            put_u64(&mut extra, 0);
+            put_u64(&mut extra, 0);
+            u32::MAX
+        } else {
+            0
+        };
+
+        let mut header = Vec::new();
+        put_u32(&mut header, LOCAL_FILE_HEADER_SIGNATURE);
+        put_u16(&mut header, self.version());
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, METHOD_STORED);
+        put_u16(&mut header, DOS_TIME);
+        put_u16(&mut header, DOS_DATE);
+        put_u32(&mut header, 0);
+        put_u32(&mut header, size);
+        put_u32(&mut header, size);
+        put_u16(&mut header, self.path.len() as u16);
+        put_u16(&mut header, extra.len() as u16);
+        header.extend_from_slice(self.path.as_bytes());
+        header.extend_from_slice(&extra);
+
+        header
+    }
+
+    fn data_descriptor(&self) -> Vec<u8> {
+        let mut descriptor = Vec::new();
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut descriptor, self.crc32);
+        if self.zip64() {
+            put_u64(&mut descriptor, self.size);
+            put_u64(&mut descriptor, self.size);
+        } else {
+            put_u32(&mut descriptor, self.size as u32);
+            put_u32(&mut descriptor, self.size as u32);
+        }
+
+        descriptor
+    }
+
+    fn central_header(&self) -> Vec<u8> {
+        let mut extra = Vec::new();
+        let size = if self.size >= u32::MAX as u64 {
+            put_u64(&mut extra, self.size);
+            put_u64(&mut extra, self.size);
+            u32::MAX
+        } else {
+            self.size as u32
+        };
+        let header_offset = if self.header_offset >= u32::MAX as u64 {
+            put_u64(&mut extra, self.header_offset);
+            u32::MAX
+        } else {
+            self.header_offset as u32
+        };
+        if !extra.is_empty() {
+            let mut field = Vec::new();
+            put_u16(&mut field, ZIP64_EXTRA_FIELD_ID);
+            put_u16(&mut field, extra.len() as u16);
+            field.extend_from_slice(&extra);
+            extra = field;
+        }
+
+        let mut header = Vec::new();
+        put_u32(&mut header, CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+        put_u16(&mut header, VERSION_ZIP64);
+        put_u16(&mut header, self.version());
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, METHOD_STORED);
+        put_u16(&mut header, DOS_TIME);
+        put_u16(&mut header, DOS_DATE);
+        put_u32(&mut header, self.crc32);
+        put_u32(&mut header, size);
+        put_u32(&mut header, size);
+        put_u16(&mut header, self.path.len() as u16);
+        put_u16(&mut header, extra.len() as u16);
+        // Comment length, disk number, internal and external attributes
+        put_u16(&mut header, 0);
+        put_u16(&mut header, 0);
+        put_u16(&mut header, 0);
+        put_u32(&mut header, 0);
+        put_u32(&mut header, header_offset);
+        header.extend_from_slice(self.path.as_bytes());
+        header.extend_from_slice(&extra);
+
+        header
+    }
+}
+
+fn central_directory(entries: &[Entry], offset: u64) -> Vec<u8> {
+    let mut directory = Vec::new();
+    for entry in entries {
+        directory.extend(entry.central_header());
+    }
+    let directory_size = directory.len() as u64;
+    let entry_count = entries.len() as u64;
+
+    let zip64 = entry_count >= u16::MAX as u64
+        || directory_size >= u32::MAX as u64
+        || offset >= u32::MAX as u64;
+    if zip64 {
+        let record_offset = offset + directory_size;
+
+        put_u32(&mut directory, ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        // Size of the rest of the record
+        put_u64(&mut directory, 44);
+        put_u16(&mut directory, VERSION_ZIP64);
+        put_u16(&mut directory, VERSION_ZIP64);
+        put_u32(&mut directory, 0);
+        put_u32(&mut directory, 0);
+        put_u64(&mut directory, entry_count);
+        put_u64(&mut directory, entry_count);
+        put_u64(&mut directory, directory_size);
+        put_u64(&mut directory, offset);
+
+        put_u32(
+            &mut directory,
+            ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE,
+        );
+        put_u32(&mut directory, 0);
+        put_u64(&mut directory, record_offset);
+        put_u32(&mut directory, 1);
+    }
+
+    put_u32(&mut directory, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+    put_u16(&mut directory, 0);
+    put_u16(&mut directory, 0);
+    put_u16(&mut directory, entry_count.min(u16::MAX as u64) as u16);
+    put_u16(&mut directory, entry_count.min(u16::MAX as u64) as u16);
+    put_u32(&mut directory, directory_size.min(u32::MAX as u64) as u32);
+    put_u32(&mut directory, offset.min(u32::MAX as u64) as u32);
+    // Comment length
+    put_u16(&mut directory, 0);
+
+    directory
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl ZipOutput for &mut Vec<u8> {
+        async fn write(&mut self, data: &[u8]) -> Result<(), JsValue> {
+            self.extend_from_slice(data);
+            Ok(())
+        }
+
+        async fn close(self) -> Result<(), JsValue> {
+            Ok(())
+        }
+    }
+
+    const GIB: u64 = 1 << 30;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+    }
+
+    fn entry(path: &str, size: u64, header_offset: u64) -> Entry {
+        Entry {
+            path: path.to_string(),
+            size,
+            crc32: 0x12345678,
+            header_offset,
+        }
+    }
+
+    /// Writes the files through a `ZipWriter` the way a received transfer is, in several pieces
+    fn stream(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut writer = ZipWriter::new(&mut archive);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            for (path, data) in files {
+                writer.start_entry(path, data.len() as u64).await.unwrap();
+                for piece in data.chunks(100) {
+                    writer.write(piece).await.unwrap();
+                }
+                writer.finish_entry().await.unwrap();
+            }
+            writer.finish().await.unwrap();
+        });
+
+        archive
+    }
+
+    #[test]
+    fn streamed_archive_reads_back() {
+        let files = [
+            ("photos/a.txt", b"hello".to_vec()),
+            ("photos/nested/b.bin", (0..1000).map(|i| i as u8).collect()),
+            ("photos/empty", Vec::new()),
+        ];
+        let archive = stream(&files);
+
+        // No comment, so the end of central directory record is the last 22 bytes
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(&archive, end), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(&archive, end + 8), files.len() as u16);
+        assert_eq!(u16_at(&archive, end + 10), files.len() as u16);
+        let directory_size = u32_at(&archive, end + 12) as usize;
+        let directory_offset = u32_at(&archive, end + 16) as usize;
+        assert_eq!(directory_offset + directory_size, end);
+
+        let mut at = directory_offset;
+        for (path, data) in &files {
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(data);
+            let crc32 = hasher.finalize();
+
+            assert_eq!(u32_at(&archive, at), CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+            assert_eq!(u32_at(&archive, at + 16), crc32);
+            assert_eq!(u32_at(&archive, at + 20), data.len() as u32);
+            assert_eq!(u32_at(&archive, at + 24), data.len() as u32);
+            let name_length = u16_at(&archive, at + 28) as usize;
+            assert_eq!(u16_at(&archive, at + 30), 0);
+            let header_offset = u32_at(&archive, at + 42) as usize;
+            assert_eq!(&archive[at + 46..at + 46 + name_length], path.as_bytes());
+            at += 46 + name_length;
+
+            let local = header_offset;
+            assert_eq!(u32_at(&archive, local), LOCAL_FILE_HEADER_SIGNATURE);
+            assert_eq!(u16_at(&archive, local + 28), 0);
+            let data_start = local + 30 + name_length;
+            assert_eq!(&archive[data_start..data_start + data.len()], &data[..]);
+
+            let descriptor = data_start + data.len();
+            assert_eq!(u32_at(&archive, descriptor), DATA_DESCRIPTOR_SIGNATURE);
+            assert_eq!(u32_at(&archive, descriptor + 4), crc32);
+            assert_eq!(u32_at(&archive, descriptor + 8), data.len() as u32);
+            assert_eq!(u32_at(&archive, descriptor + 12), data.len() as u32);
+        }
+        assert_eq!(at, end);
+    }
+
+    #[test]
+    fn empty_archive() {
+        let archive = stream(&[]);
+
+        assert_eq!(archive.len(), 22);
+        assert_eq!(u32_at(&archive, 0), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(&archive, 8), 0);
+        assert_eq!(u32_at(&archive, 12), 0);
+        assert_eq!(u32_at(&archive, 16), 0);
+    }
+
+    #[test]
+    fn small_entry_headers() {
+        let entry = entry("photos/a.txt", 5, 100);
+
+        let local = entry.local_header();
+        assert_eq!(u32_at(&local, 0), LOCAL_FILE_HEADER_SIGNATURE);
+        assert_eq!(u16_at(&local, 4), VERSION_DEFAULT);
+        assert_eq!(u16_at(&local, 6), FLAGS);
+        assert_eq!(u16_at(&local, 8), METHOD_STORED);
+        // Left for the data descriptor
+        assert_eq!(u32_at(&local, 14), 0);
+        assert_eq!(u32_at(&local, 18), 0);
+        assert_eq!(u32_at(&local, 22), 0);
+        assert_eq!(u16_at(&local, 26), entry.path.len() as u16);
+        assert_eq!(u16_at(&local, 28), 0);
+        assert_eq!(&local[30..], entry.path.as_bytes());
+
+        let descriptor = entry.data_descriptor();
+        assert_eq!(descriptor.len(), 16);
+        assert_eq!(u32_at(&descriptor, 0), DATA_DESCRIPTOR_SIGNATURE);
+        assert_eq!(u32_at(&descriptor, 4), 0x12345678);
+        assert_eq!(u32_at(&descriptor, 8), 5);
+        assert_eq!(u32_at(&descriptor, 12), 5);
+
+        let central = entry.central_header();
+        assert_eq!(u32_at(&central, 0), CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+        assert_eq!(u16_at(&central, 6), VERSION_DEFAULT);
+        assert_eq!(u32_at(&central, 16), 0x12345678);
+        assert_eq!(u32_at(&central, 20), 5);
+        assert_eq!(u32_at(&central, 24), 5);
+        assert_eq!(u16_at(&central, 30), 0);
+        assert_eq!(u32_at(&central, 42), 100);
+        assert_eq!(&central[46..], entry.path.as_bytes());
+    }
+
+    #[test]
+    fn large_entry_uses_zip64_fields() {
+        let entry = entry("big.bin", 5 * GIB, 0);
+
+        let local = entry.local_header();
+        assert_eq!(u16_at(&local, 4), VERSION_ZIP64);
+        assert_eq!(u32_at(&local, 18), u32::MAX);
+        assert_eq!(u32_at(&local, 22), u32::MAX);
+        assert_eq!(u16_at(&local, 28), 20);
+        let extra = 30 + entry.path.len();
+        assert_eq!(u16_at(&local, extra), ZIP64_EXTRA_FIELD_ID);
+        assert_eq!(u16_at(&local, extra + 2), 16);
+        assert_eq!(u64_at(&local, extra + 4), 0);
+        assert_eq!(u64_at(&local, extra + 12), 0);
+        assert_eq!(local.len(), extra + 20);
+
+        let descriptor = entry.data_descriptor();
+        assert_eq!(descriptor.len(), 24);
+        assert_eq!(u64_at(&descriptor, 8), 5 * GIB);
+        assert_eq!(u64_at(&descriptor, 16), 5 * GIB);
+
+        let central = entry.central_header();
+        assert_eq!(u16_at(&central, 6), VERSION_ZIP64);
+        assert_eq!(u32_at(&central, 20), u32::MAX);
+        assert_eq!(u32_at(&central, 24), u32::MAX);
+        assert_eq!(u32_at(&central, 42), 0);
+        let extra = 46 + entry.path.len();
+        assert_eq!(u16_at(&central, extra), ZIP64_EXTRA_FIELD_ID);
+        assert_eq!(u16_at(&central, extra + 2), 16);
+        assert_eq!(u64_at(&central, extra + 4), 5 * GIB);
+        assert_eq!(u64_at(&central, extra + 12), 5 * GIB);
+        assert_eq!(central.len(), extra + 20);
+    }
+
+    #[test]
+    fn far_entry_stores_zip64_offset() {
+        let entry = entry("late.txt", 10, 5 * GIB);
+
+        let central = entry.central_header();
+        assert_eq!(u32_at(&central, 20), 10);
+        assert_eq!(u32_at(&central, 24), 10);
+        assert_eq!(u32_at(&central, 42), u32::MAX);
+        let extra = 46 + entry.path.len();
+        assert_eq!(u16_at(&central, extra), ZIP64_EXTRA_FIELD_ID);
+        assert_eq!(u16_at(&central, extra + 2), 8);
+        assert_eq!(u64_at(&central, extra + 4), 5 * GIB);
+        assert_eq!(central.len(), extra + 12);
+    }
+
+    #[test]
+    fn small_archive_has_plain_trailer() {
+        let entries = [entry("a.txt", 5, 0), entry("b.txt", 7, 40)];
+        let trailer = central_directory(&entries, 80);
+
+        let directory_size = entries
+            .iter()
+            .map(|entry| entry.central_header().len())
+            .sum::<usize>();
+        let end = directory_size;
+        assert_eq!(u32_at(&trailer, end), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(&trailer, end + 8), 2);
+        assert_eq!(u16_at(&trailer, end + 10), 2);
+        assert_eq!(u32_at(&trailer, end + 12), directory_size as u32);
+        assert_eq!(u32_at(&trailer, end + 16), 80);
+        assert_eq!(trailer.len(), end + 22);
+    }
+
+    #[test]
+    fn large_archive_has_zip64_trailer() {
+        let entries = [entry("a.bin", 3 * GIB, 0), entry("b.bin", 3 * GIB, 3 * GIB)];
+        let offset = 6 * GIB + 200;
+        let trailer = central_directory(&entries, offset);
+
+        let directory_size = entries
+            .iter()
+            .map(|entry| entry.central_header().len())
+            .sum::<usize>();
+        let record = directory_size;
+        assert_eq!(
+            u32_at(&trailer, record),
+            ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE
+        );
+        assert_eq!(u64_at(&trailer, record + 4), 44);
+        assert_eq!(u64_at(&trailer, record + 24), 2);
+        assert_eq!(u64_at(&trailer, record + 32), 2);
+        assert_eq!(u64_at(&trailer, record + 40), directory_size as u64);
+        assert_eq!(u64_at(&trailer, record + 48), offset);
+
+        let locator = record + 56;
+        assert_eq!(
+            u32_at(&trailer, locator),
+            ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE
+        );
+        assert_eq!(
+            u64_at(&trailer, locator + 8),
+            offset + directory_size as u64
+        );
+        assert_eq!(u32_at(&trailer, locator + 16), 1);
+
+        let end = locator + 20;
+        assert_eq!(u32_at(&trailer, end), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(&trailer, end + 8), 2);
+        assert_eq!(u32_at(&trailer, end + 12), directory_size as u32);
+        assert_eq!(u32_at(&trailer, end + 16), u32::MAX);
+        assert_eq!(trailer.len(), end + 22);
+    }
+
+    #[test]
+    fn many_entries_use_zip64_trailer() {
+        let entries = (0..u16::MAX as u64)
+            .map(|i| entry("f", 0, i * 40))
+            .collect::<Vec<_>>();
+        let offset = u16::MAX as u64 * 40;
+        let trailer = central_directory(&entries, offset);
+
+        let end = trailer.len() - 22;
+        assert_eq!(u32_at(&trailer, end), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(&trailer, end + 8), u16::MAX);
+        assert_eq!(u16_at(&trailer, end + 10), u16::MAX);
+        assert_eq!(u32_at(&trailer, end + 16), offset as u32);
+
+        let record = end - 20 - 56;
+        assert_eq!(
+            u32_at(&trailer, record),
+            ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE
+        );
+        assert_eq!(u64_at(&trailer, record + 24), u16::MAX as u64);
+        assert_eq!(u64_at(&trailer, record + 32), u16::MAX as u64);
+    }
+}
@@ -0,0 +1,77 @@
+// Streams received files to disk as downloads, for browsers without the File System Access API.
+// The page sends each download's data over a MessagePort, then opens /download/<id>, which this
+// worker answers with a stream fed by that port.
+
+const downloads = new Map();
+
+self.addEventListener("install", () => self.skipWaiting());
+
+self.addEventListener("activate", (event) => event.waitUntil(self.clients.claim()));
+
+self.addEventListener("message", (event) => {
+    if (event.data?.type !== "download") {
+        return;
+    }
+
+    const { id, filename, size, mimeType } = event.data;
+    const port = event.ports[0];
+
+    // "pulled" is posted once per chunk, when the stream has room for another. That keeps the
+    // page from getting ahead of the disk
+    let pendingPull = false;
+    const stream = new ReadableStream(
+        {
+            start(controller) {
+                port.onmessage = ({ data }) => {
+                    if (data === "end") {
+                        controller.close();
+                        port.close();
+                    } else if (data === "abort") {
+                        controller.error(new Error("Download aborted"));
+                        port.close();
+                    } else {
+                        controller.enqueue(new Uint8Array(data));
+                        if (controller.desiredSize > 0) {
+                            port.postMessage("pulled");
+                        } else {
+                            pendingPull = true;
+                        }
+                    }
+                };
+            },
+            pull() {
+                if (pendingPull) {
+                    pendingPull = false;
+                    port.postMessage("pulled");
+                }
+            },
+            cancel() {
+                port.postMessage("cancelled");
+                port.close();
+            },
+        },
+        { highWaterMark: 16 },
+    );
+
+    const headers = {
+        "Content-Type": mimeType || "application/octet-stream",
+        "Content-Disposition": `attachment; filename*=UTF-8''${encodeURIComponent(filename)}`,
+    };
+    if (size !== undefined && size !== null) {
+        headers["Content-Length"] = String(size);
+    }
+
+    downloads.set(id, { stream, headers });
+    port.postMessage("ready");
+});
+
+self.addEventListener("fetch", (event) => {
+    const match = new URL(event.request.url).pathname.match(/\/download\/([^/]+)$/);
+    const download = match && downloads.get(match[1]);
+    if (!download) {
+        return;
+    }
+
+    downloads.delete(match[1]);
+    event.respondWith(new Response(download.stream, { headers: download.headers }));
+});